@@ -0,0 +1,4656 @@
+//! ggrep: a recursive grep-like tool. The binary is a thin wrapper around
+//! [`run`]; [`Searcher`] exposes the search engine to other crates.
+
+use std::{collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque}, env, ffi::OsString, fs::{self, File}, hash::{Hash, Hasher}, io::{self, BufRead, BufReader, Cursor, Read, Write}, ops::{ControlFlow, Range}, path::{Path, PathBuf, MAIN_SEPARATOR}, process::{Command, ExitCode, Stdio}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex}, time::{Duration, Instant, SystemTime}};
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap_complete::Shell;
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::read::MultiGzDecoder;
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use memchr::memmem::Finder;
+use memmap2::Mmap;
+use regex::{Regex, RegexBuilder};
+use ansi_term::Colour;
+use atty::Stream;
+use rayon::prelude::*;
+use serde_json::json;
+
+/// Color modes for output highlighting.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Regex engines selectable with --engine.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum Engine {
+    /// The regex crate: linear-time matching, no lookaround or backreferences
+    Default,
+    /// fancy-regex: adds lookaround and backreferences by backtracking
+    Fancy,
+}
+
+/// Colors selectable for highlighted output fields.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum ColorName {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl ColorName {
+    fn ansi(self) -> Colour {
+        match self {
+            ColorName::Red => Colour::Red,
+            ColorName::Green => Colour::Green,
+            ColorName::Yellow => Colour::Yellow,
+            ColorName::Blue => Colour::Blue,
+            ColorName::Magenta => Colour::Purple,
+            ColorName::Cyan => Colour::Cyan,
+            ColorName::White => Colour::White,
+        }
+    }
+}
+
+/// Keys for ordering searched files with --sort and --sortr.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum SortKey {
+    Path,
+    Modified,
+    Created,
+}
+
+/// Handling of invalid UTF-8, selected with --encoding-errors.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum EncodingErrors {
+    /// Replace undecodable bytes with U+FFFD and keep searching.
+    Lossy,
+    /// Drop the whole file with a warning.
+    Skip,
+    /// Stop at the first undecodable line and report an error.
+    Strict,
+}
+
+/// Input encoding selected with --encoding.
+#[derive(Copy, Clone, Debug)]
+enum InputEncoding {
+    /// Decode according to a byte-order mark, passing other input through.
+    Auto,
+    /// Guess each input's encoding from its first bytes.
+    Detect,
+    Fixed(&'static Encoding),
+}
+
+/// Parses an --encoding value: "auto", "auto-detect", or any WHATWG
+/// encoding label.
+fn parse_encoding(label: &str) -> Result<InputEncoding, String> {
+    if label.eq_ignore_ascii_case("auto") {
+        return Ok(InputEncoding::Auto);
+    }
+    if label.eq_ignore_ascii_case("auto-detect") {
+        return Ok(InputEncoding::Detect);
+    }
+    Encoding::for_label(label.as_bytes())
+        .map(InputEncoding::Fixed)
+        .ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+/// Parses a size such as 512, 512K, 10M, or 2G (binary multiples) into bytes.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}'", size))
+}
+
+/// Parses a point in time: a UTC date such as 2024-05-01 or
+/// 2024-05-01T13:30[:00][Z], or an age such as 90s, 30m, 12h, 2d, or 1w
+/// counted back from now.
+fn parse_time(value: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid date or age '{}'", value);
+    let unit = match value.char_indices().last() {
+        Some((i, 's')) => Some((i, 1)),
+        Some((i, 'm')) => Some((i, 60)),
+        Some((i, 'h')) => Some((i, 60 * 60)),
+        Some((i, 'd')) => Some((i, 24 * 60 * 60)),
+        Some((i, 'w')) => Some((i, 7 * 24 * 60 * 60)),
+        _ => None,
+    };
+    if let Some((i, seconds)) = unit {
+        if let Ok(n) = value[..i].parse::<u64>() {
+            let age = n.checked_mul(seconds).ok_or_else(invalid)?;
+            return SystemTime::now()
+                .checked_sub(Duration::from_secs(age))
+                .ok_or_else(invalid);
+        }
+    }
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00"));
+    let number = |part: Option<&str>, range: std::ops::RangeInclusive<u64>| {
+        part.and_then(|p| p.parse::<u64>().ok())
+            .filter(|n| range.contains(n))
+            .ok_or_else(invalid)
+    };
+    let mut ymd = date.split('-');
+    let year = number(ymd.next(), 1970..=9999)?;
+    let month = number(ymd.next(), 1..=12)?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let day = number(ymd.next(), 1..=month_days)?;
+    let mut hms = time.strip_suffix('Z').unwrap_or(time).split(':');
+    let hour = number(hms.next(), 0..=23)?;
+    let minute = number(hms.next(), 0..=59)?;
+    let second = number(hms.next().or(Some("0")), 0..=59)?;
+    if ymd.next().is_some() || hms.next().is_some() {
+        return Err(invalid());
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, counting
+    // years from March so the leap day falls at the end.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era_days = y / 400 * 146_097;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era_days + day_of_era)
+        .checked_sub(719_468)
+        .ok_or_else(invalid)?;
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Simple recursive grep-like tool in Rust, avoiding shell glob limits.
+#[derive(Parser, Clone, Debug)]
+#[command(
+    name = "ggrep",
+    author,
+    version,
+    about = "Recursive grep in Rust without shell globs",
+    long_about = None,
+    disable_help_flag = true,
+    args_override_self = true
+)]
+struct Args {
+    /// Pattern to search for (regex or literal); optional when -e, -f, --literal-file,
+    /// --files, or --name-match is used
+    #[arg(required_unless_present_any = [
+        "regexp",
+        "pattern_files",
+        "literal_files",
+        "files",
+        "name_match",
+        "generate_completions",
+        "generate_man",
+    ])]
+    keyword: Option<String>,
+
+    /// Directories or files to search (default "."; "-" reads stdin); a glob
+    /// such as 'src/**/*.rs' searches the files it matches
+    #[arg(value_name = "PREFIX")]
+    prefixes: Vec<String>,
+
+    /// Case-insensitive match
+    #[arg(short = 'i', long)]
+    ignore_case: bool,
+
+    /// Case-insensitive fixed-string search; shorthand for -F -i
+    #[arg(long)]
+    ignore_case_fixed: bool,
+
+    /// Case-insensitive match if the pattern has no uppercase letters (-i wins)
+    #[arg(short = 'S', long)]
+    smart_case: bool,
+
+    /// Invert match
+    #[arg(short = 'v', long)]
+    invert_match: bool,
+
+    /// Count matches per file
+    #[arg(short = 'c', long)]
+    count: bool,
+
+    /// Count every match per file rather than matching lines (implies -c)
+    #[arg(long, conflicts_with = "invert_match")]
+    count_matches: bool,
+
+    /// With -c, print totals per immediate subdirectory of each prefix, then
+    /// a grand total, instead of a count per file (implies -c)
+    #[arg(long, conflicts_with_all = ["json", "list_files", "files_without_match"])]
+    count_by_dir: bool,
+
+    /// With -c, also print files without any match, with a count of 0
+    #[arg(long)]
+    include_zero: bool,
+
+    /// Print only the matched parts of lines, one per output line
+    #[arg(short = 'o', long, conflicts_with = "invert_match")]
+    only_matching: bool,
+
+    /// Print each distinct matched text with how often it matched across
+    /// all files ("COUNT<TAB>TEXT"), most frequent first
+    #[arg(
+        long,
+        conflicts_with_all = ["invert_match", "count", "list_files", "files_without_match", "json"]
+    )]
+    tally: bool,
+
+    /// Cut printed lines longer than N characters, noting how many were omitted
+    #[arg(long, value_name = "N")]
+    max_columns: Option<usize>,
+
+    /// With --max-columns, slide the shown window so the first match is visible
+    #[arg(long, requires = "max_columns")]
+    max_columns_preview: bool,
+
+    /// Strip leading whitespace from printed lines
+    #[arg(long)]
+    trim: bool,
+
+    /// Show spaces and tabs inside highlighted matches as "·" and "→"
+    /// (only when output is colored)
+    #[arg(long)]
+    show_whitespace: bool,
+
+    /// Print every match as path:line:column:text for Vim's quickfix list
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "invert_match", "only_matching", "count", "list_files",
+            "files_without_match", "json", "multiline",
+        ]
+    )]
+    vimgrep: bool,
+
+    /// Stop reading a file after N matching lines
+    #[arg(short = 'm', long, value_name = "N")]
+    max_count: Option<usize>,
+
+    /// Stop the whole search after N matching lines across all files
+    #[arg(long, value_name = "N")]
+    max_matches: Option<usize>,
+
+    /// Print each distinct matching line only once across all files; later
+    /// copies are skipped
+    #[arg(long, conflicts_with = "multiline")]
+    unique: bool,
+
+    /// Like --unique, but forget the lines seen at the start of each file
+    #[arg(long, conflicts_with_all = ["multiline", "unique"])]
+    unique_per_file: bool,
+
+    /// Print nothing; exit with success as soon as a match is found
+    #[arg(short = 'q', long, visible_alias = "silent")]
+    quiet: bool,
+
+    /// Suppress error messages about unreadable files; the exit status is kept
+    #[arg(short = 's', long)]
+    no_messages: bool,
+
+    /// Print the 0-based byte offset of each matching line (or match with -o)
+    #[arg(short = 'b', long)]
+    byte_offset: bool,
+
+    /// Print the 1-based character column of the first match on each line
+    #[arg(long)]
+    column: bool,
+
+    /// Follow file names with a NUL byte instead of ':' or a newline
+    #[arg(short = 'Z', long)]
+    null: bool,
+
+    /// End each output record (a line, or a whole block with -U) with a NUL
+    /// byte instead of a newline; disables color
+    #[arg(long)]
+    print0_matches: bool,
+
+    /// Print paths with CHAR in place of the platform's separator (e.g. '/'
+    /// on Windows)
+    #[arg(long, value_name = "CHAR")]
+    path_separator: Option<char>,
+
+    /// Print paths made absolute (symlinks resolved) instead of as given
+    #[arg(long, overrides_with = "relative_path")]
+    absolute_path: bool,
+
+    /// Print paths relative to the current directory instead of as given
+    #[arg(
+        long,
+        visible_alias = "with-filename-relative",
+        overrides_with = "absolute_path"
+    )]
+    relative_path: bool,
+
+    /// Print matching lines with every match replaced by REPL ($1, ${name} expand)
+    #[arg(long, value_name = "REPL")]
+    replace: Option<String>,
+
+    /// Rewrite matching files with --replace applied, keeping a copy with
+    /// the extension SUFFIX if given (--in-place=bak)
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        requires = "replace",
+        conflicts_with = "invert_match"
+    )]
+    in_place: Option<String>,
+
+    /// Search binary files as text instead of reporting "Binary file matches"
+    #[arg(short = 'a', long, visible_alias = "binary")]
+    text: bool,
+
+    /// Let matches span lines; reads each file fully into memory
+    #[arg(short = 'U', long, conflicts_with = "invert_match")]
+    multiline: bool,
+
+    /// Treat "\r\n" as a line terminator for ^ and $ with -U, hiding the "\r"
+    #[arg(long)]
+    crlf: bool,
+
+    /// Treat input and output lines as terminated by NUL instead of newline
+    #[arg(short = 'z', long)]
+    null_data: bool,
+
+    /// Transparently decompress and search .gz files
+    #[arg(long)]
+    search_zip: bool,
+
+    /// Search each file inside .zip, .tar, .tar.gz, and .tgz archives,
+    /// shown as "archive.zip!entry"
+    #[arg(long)]
+    search_archives: bool,
+
+    /// Decode input from this encoding (e.g. utf-16le, latin1, auto to sniff a
+    /// BOM, or auto-detect to guess it per file)
+    #[arg(long, value_name = "NAME", value_parser = parse_encoding)]
+    encoding: Option<InputEncoding>,
+
+    /// How to treat invalid UTF-8 in text files: replace it with U+FFFD,
+    /// skip the file with a warning, or fail the file
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = EncodingErrors::Lossy)]
+    encoding_errors: EncodingErrors,
+
+    /// List only filenames with matches
+    #[arg(short = 'l', long, conflicts_with_all = ["count", "count_matches"])]
+    list_files: bool,
+
+    /// List only filenames without any match
+    #[arg(
+        short = 'L',
+        long,
+        conflicts_with_all = ["list_files", "count", "count_matches"]
+    )]
+    files_without_match: bool,
+
+    /// Print the files that would be searched, without searching them
+    #[arg(long)]
+    files: bool,
+
+    /// Match the pattern against each file's path instead of its contents,
+    /// printing the paths that match
+    #[arg(long, conflicts_with = "files")]
+    name_match: bool,
+
+    /// Pattern to search for; repeatable, a line matches if any pattern does
+    #[arg(short = 'e', long, value_name = "PATTERN")]
+    regexp: Vec<String>,
+
+    /// Only search files that also contain PATTERN somewhere (repeatable)
+    #[arg(long = "and", value_name = "PATTERN", conflicts_with = "invert_match")]
+    and_patterns: Vec<String>,
+
+    /// Read patterns from FILE, one per line (repeatable)
+    #[arg(short = 'f', long = "file", value_name = "FILE")]
+    pattern_files: Vec<PathBuf>,
+
+    /// Search for the entire contents of FILE as one fixed string (repeatable;
+    /// use -U for contents spanning several lines)
+    #[arg(long = "literal-file", value_name = "FILE")]
+    literal_files: Vec<PathBuf>,
+
+    /// Fixed-string search (no regex)
+    #[arg(short = 'F', long)]
+    fixed_strings: bool,
+
+    /// Match whole words only
+    #[arg(short = 'w', long)]
+    word_regexp: bool,
+
+    /// Match only whole lines
+    #[arg(short = 'x', long)]
+    line_regexp: bool,
+
+    /// Match \b, \w, \d, \s, and case folding as ASCII only, so "café" splits at
+    /// the "é" under -w; faster on large classes
+    #[arg(long)]
+    ascii: bool,
+
+    /// Regex engine; fancy supports lookaround and backreferences such as
+    /// "(\w+)\s+\1" but may be much slower
+    #[arg(long, value_enum, value_name = "ENGINE", default_value_t = Engine::Default)]
+    engine: Engine,
+
+    /// Maximum size of the compiled pattern (bytes, or with a K, M, or G suffix)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "10M")]
+    regex_size_limit: u64,
+
+    /// Maximum size of the lazy DFA cache used while matching
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "2M")]
+    dfa_size_limit: u64,
+
+    /// Colorize matches: auto, always, or never
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Color of matched text
+    #[arg(long, value_enum, value_name = "COLOR", default_value_t = ColorName::Red)]
+    color_match: ColorName,
+
+    /// Color of line numbers (uncolored by default)
+    #[arg(long, value_enum, value_name = "COLOR")]
+    color_line: Option<ColorName>,
+
+    /// Color of file names
+    #[arg(long, value_enum, value_name = "COLOR", default_value_t = ColorName::Magenta)]
+    color_path: ColorName,
+
+    /// Maximum directory depth to descend (0 = only the prefix itself)
+    #[arg(short = 'd', long, value_name = "N", default_value_t = DEFAULT_DEPTH)]
+    max_depth: usize,
+
+    /// Search only the files directly inside the prefix (same as -d 0)
+    #[arg(long, overrides_with = "recursive")]
+    no_recursive: bool,
+
+    /// Descend into subdirectories, up to --max-depth (the default)
+    #[arg(
+        short = 'r',
+        long,
+        visible_short_alias = 'R',
+        overrides_with = "no_recursive"
+    )]
+    recursive: bool,
+
+    /// File extension to search (repeatable; replaces the built-in list)
+    #[arg(long = "ext", value_name = "EXT")]
+    extensions: Vec<String>,
+
+    /// Search all files regardless of extension
+    #[arg(long, conflicts_with = "extensions")]
+    all_files: bool,
+
+    /// Only search files whose name or path matches GLOB (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip files whose name or path matches GLOB (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Never descend into directories whose name or path matches (repeatable)
+    #[arg(long, value_name = "NAME-or-GLOB")]
+    exclude_dir: Vec<String>,
+
+    /// Match --include, --exclude, and --exclude-dir globs case-insensitively
+    #[arg(long)]
+    glob_case_insensitive: bool,
+
+    /// Only search files whose path (without a leading "./") matches REGEX
+    #[arg(long, value_name = "REGEX")]
+    path_filter: Option<String>,
+
+    /// Search the output of `CMD <path>` instead of each file's contents
+    #[arg(long, value_name = "CMD")]
+    pre: Option<String>,
+
+    /// Only run --pre on files whose name or path matches (repeatable)
+    #[arg(long, value_name = "GLOB", requires = "pre")]
+    pre_glob: Vec<String>,
+
+    /// Print N lines of trailing context after each match
+    #[arg(short = 'A', long, value_name = "N")]
+    after_context: Option<usize>,
+
+    /// Print N lines of leading context before each match
+    #[arg(short = 'B', long, value_name = "N")]
+    before_context: Option<usize>,
+
+    /// Print N lines of context before and after each match
+    #[arg(short = 'C', long, value_name = "N")]
+    context: Option<usize>,
+
+    /// Print every line, highlighting matches; other lines are printed as context
+    #[arg(
+        long,
+        conflicts_with_all = ["only_matching", "multiline", "before_context", "after_context", "context", "json"]
+    )]
+    passthru: bool,
+
+    /// After the matches of each matching file, print its last N lines as
+    /// context (e.g. a footer that the matches refer to)
+    #[arg(long, value_name = "N", conflicts_with_all = ["multiline", "json", "vimgrep"])]
+    file_tail: Option<usize>,
+
+    /// Line printed between non-adjacent groups of context
+    #[arg(long, value_name = "STR", default_value = "--")]
+    group_separator: String,
+
+    /// Before each group of matches, print the line opening the function or
+    /// block around it, marked with '=' (Python by indentation, C-like
+    /// languages by braces)
+    #[arg(long, conflicts_with_all = ["json", "only_matching", "vimgrep", "multiline", "passthru"])]
+    context_block: bool,
+
+    /// Separator after the path and line number of context lines
+    #[arg(long, value_name = "STR", default_value = "-")]
+    context_separator: String,
+
+    /// Separator between the path, line number, column, offset, and text of
+    /// matches and after the path of counts (not used by --vimgrep)
+    #[arg(long, value_name = "STR", default_value = ":")]
+    field_separator: String,
+
+    /// Number of worker threads; 0 (the default) picks one per CPU, or a
+    /// single thread when there are fewer than 16 files to search
+    #[arg(long, value_name = "N")]
+    threads: Option<usize>,
+
+    /// Print results as JSON Lines (one object per match or per file, each
+    /// tagged with a "type"), ending with a "summary" object
+    #[arg(long)]
+    json: bool,
+
+    /// Order files by path, modified, or created time (default: path)
+    #[arg(long, value_enum, value_name = "KEY")]
+    sort: Option<SortKey>,
+
+    /// Order files by path, as with --sort path
+    #[arg(long, conflicts_with_all = ["sort", "sortr"])]
+    sort_files: bool,
+
+    /// Like --sort, in descending order
+    #[arg(long, value_enum, value_name = "KEY", conflicts_with = "sort")]
+    sortr: Option<SortKey>,
+
+    /// Print search statistics to stderr when done
+    #[arg(long)]
+    stats: bool,
+
+    /// Show a running count of searched files on stderr (terminals only)
+    #[arg(long)]
+    progress: bool,
+
+    /// Reuse results for files unchanged since a previous run with the same
+    /// pattern and flags, kept under ~/.cache/ggrep
+    #[arg(long)]
+    cache: bool,
+
+    /// Don't respect .gitignore, .ignore, or global gitignore rules
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Search hidden files and directories (names starting with ".")
+    #[arg(long)]
+    hidden: bool,
+
+    /// Follow symbolic links while walking (off by default)
+    #[arg(long)]
+    follow: bool,
+
+    /// With --follow, skip symlinks whose target lies outside the prefix
+    /// being walked (listed on stderr with --stats)
+    #[arg(long, requires = "follow")]
+    no_escape_root: bool,
+
+    /// Skip files larger than SIZE (bytes, or with a K, M, or G suffix)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_filesize: Option<u64>,
+
+    /// Only search files modified at or after DATE (UTC, e.g. 2024-05-01) or
+    /// within an age such as 2d, 12h, 30m
+    #[arg(long, value_name = "DATE", value_parser = parse_time)]
+    modified_after: Option<SystemTime>,
+
+    /// Only search files modified before DATE (UTC) or more than an age ago
+    #[arg(long, value_name = "DATE", value_parser = parse_time)]
+    modified_before: Option<SystemTime>,
+
+    /// Memory-map every file instead of only large ones
+    #[arg(long, overrides_with = "no_mmap")]
+    mmap: bool,
+
+    /// Never memory-map files
+    #[arg(long, overrides_with = "mmap")]
+    no_mmap: bool,
+
+    /// Print the file path once above its matches instead of on every line
+    #[arg(long)]
+    heading: bool,
+
+    /// Never print file names in front of output lines
+    #[arg(short = 'h', long, overrides_with = "with_filename")]
+    no_filename: bool,
+
+    /// Always print file names, even when searching a single file
+    #[arg(short = 'H', long, overrides_with = "no_filename")]
+    with_filename: bool,
+
+    /// Print line numbers (the default when output is a terminal)
+    #[arg(short = 'n', long, overrides_with = "no_line_number")]
+    line_number: bool,
+
+    /// Never print line numbers
+    #[arg(short = 'N', long, overrides_with = "line_number")]
+    no_line_number: bool,
+
+    /// Ignore the config file
+    #[arg(long)]
+    no_config: bool,
+
+    /// Print a completion script for SHELL to stdout
+    #[arg(long, value_name = "SHELL", hide = true)]
+    generate_completions: Option<Shell>,
+
+    /// Print a roff man page to stdout
+    #[arg(long, hide = true)]
+    generate_man: bool,
+
+    /// Print help
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+}
+
+/// Label used in place of a path when searching standard input.
+const STDIN_LABEL: &str = "(standard input)";
+
+/// Pattern used when no patterns were collected (e.g. an empty -f file).
+const NEVER_MATCHES: &str = r"[^\x00-\x{10FFFF}]";
+
+/// UTF-8 byte-order mark, stripped from the first line of an input.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Number of leading bytes inspected for a NUL when detecting binary files.
+const BINARY_PEEK: usize = 8192;
+
+/// Number of leading bytes --encoding auto-detect guesses an encoding from.
+const DETECT_PEEK: usize = 8192;
+
+/// Fewest non-ASCII bytes --encoding auto-detect trusts a guess on; with
+/// fewer, input is read as UTF-8.
+const DETECT_MIN_NON_ASCII: usize = 8;
+
+/// Files at least this large are memory-mapped unless --no-mmap is given.
+const MMAP_THRESHOLD: u64 = 1 << 20;
+
+/// Files searched in parallel before their output is printed in order; at
+/// most this many files' output is held in memory at once.
+const STREAM_WINDOW: usize = 64;
+
+/// Minimum time between --progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// With automatic threading, fewer files than this are searched on the main
+/// thread. This is a heuristic, not a measured crossover: on one CPU the
+/// pool's start-up cost is lost in run-to-run noise.
+const AUTO_PARALLEL_MIN_FILES: usize = 16;
+
+/// Search depth and supported file extensions.
+const DEFAULT_DEPTH: usize = 7;
+const EXTENSIONS: &[&str] = &[
+    "cpp", "h", "txt", "html", "php", "c", "css", "json", "py", "js",
+];
+
+/// Extensions whose blocks --context-block finds by braces.
+const BRACE_LANGUAGES: &[&str] = &[
+    "c", "h", "cc", "cpp", "hpp", "cs", "css", "go", "java", "js", "jsx", "kt", "php", "rs",
+    "scala", "swift", "ts", "tsx",
+];
+
+/// Keywords starting block openers that --context-block looks past, so a
+/// match is placed in its function rather than in an `if` inside it.
+const CONTROL_KEYWORDS: &[&str] = &[
+    "if", "else", "for", "while", "do", "loop", "switch", "match", "case", "default", "try",
+    "catch", "finally", "return",
+];
+
+/// Per-file filters applied to every walked file.
+#[derive(Clone)]
+struct FileFilter {
+    /// Allowed extensions; `None` means every extension is searched.
+    extensions: Option<HashSet<String>>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    /// Directories pruned from the walk entirely.
+    exclude_dirs: Option<GlobSet>,
+    path_filter: Option<Regex>,
+    /// Whether .gz files are judged by their inner extension.
+    search_zip: bool,
+    /// Whether archives pass regardless of extension (their entries are
+    /// filtered instead).
+    search_archives: bool,
+    /// Modification-time window from --modified-after/--modified-before.
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+}
+
+impl FileFilter {
+    fn new(args: &Args) -> Self {
+        let extensions = if args.all_files {
+            None
+        } else if args.extensions.is_empty() {
+            Some(EXTENSIONS.iter().map(|ext| ext.to_string()).collect())
+        } else {
+            Some(
+                args.extensions
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_string())
+                    .collect(),
+            )
+        };
+        FileFilter {
+            extensions,
+            include: build_globset(&args.include, args.glob_case_insensitive),
+            exclude: build_globset(&args.exclude, args.glob_case_insensitive),
+            exclude_dirs: build_globset(&args.exclude_dir, args.glob_case_insensitive),
+            path_filter: args.path_filter.as_deref().map(|pattern| {
+                Regex::new(pattern).unwrap_or_else(|e| {
+                    eprintln!("Invalid path filter '{}': {}", pattern, e);
+                    std::process::exit(2);
+                })
+            }),
+            search_zip: args.search_zip,
+            search_archives: args.search_archives,
+            modified_after: args.modified_after,
+            modified_before: args.modified_before,
+        }
+    }
+
+    /// Returns true if the file at `path` should be searched.
+    fn matches(&self, path: &Path) -> bool {
+        let archive = self.search_archives && archive_kind(path).is_some();
+        if !archive && !self.allows_extension(path) {
+            return false;
+        }
+        let globbed = |set: &GlobSet| glob_matches(set, path);
+        let relative = path.strip_prefix(".").unwrap_or(path);
+        self.include.as_ref().is_none_or(globbed)
+            && !self.exclude.as_ref().is_some_and(globbed)
+            && self
+                .path_filter
+                .as_ref()
+                .is_none_or(|re| re.is_match(&relative.to_string_lossy()))
+    }
+
+    /// Returns true if `path` has one of the searched extensions.
+    fn allows_extension(&self, path: &Path) -> bool {
+        let Some(allowed) = &self.extensions else {
+            return true;
+        };
+        // With --search-zip, app.log.gz is filtered as a .log file.
+        let inner = path.file_stem().map(Path::new);
+        let ext = match inner {
+            Some(inner) if self.search_zip && is_gzip(path) => inner.extension(),
+            _ => path.extension(),
+        }
+        .and_then(|s| s.to_str());
+        ext.is_some_and(|ext| allowed.contains(ext))
+    }
+
+    /// Returns true if the file's modification time lies within the
+    /// --modified-after/--modified-before window. Files whose time cannot be
+    /// read are skipped once a window is set.
+    fn modified_in_window(&self, entry: &ignore::DirEntry) -> bool {
+        if self.modified_after.is_none() && self.modified_before.is_none() {
+            return true;
+        }
+        let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) else {
+            return false;
+        };
+        self.modified_after.is_none_or(|after| modified >= after)
+            && self.modified_before.is_none_or(|before| modified < before)
+    }
+}
+
+/// Returns true if `set` matches the file name or the path of `path`,
+/// ignoring a leading "./".
+fn glob_matches(set: &GlobSet, path: &Path) -> bool {
+    let relative = path.strip_prefix(".").unwrap_or(path);
+    set.is_match(relative) || path.file_name().is_some_and(|name| set.is_match(name))
+}
+
+/// Compiles glob patterns into a set, or `None` if there are none; exits with
+/// code 2 on an invalid glob.
+fn build_globset(globs: &[String], case_insensitive: bool) -> Option<GlobSet> {
+    if globs.is_empty() {
+        return None;
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let built = GlobBuilder::new(glob)
+            .case_insensitive(case_insensitive)
+            .build();
+        let glob = built.unwrap_or_else(|e| {
+            eprintln!("Invalid glob '{}': {}", glob, e);
+            std::process::exit(2);
+        });
+        builder.add(glob);
+    }
+    Some(builder.build().unwrap_or_else(|e| {
+        eprintln!("Invalid glob set: {}", e);
+        std::process::exit(2);
+    }))
+}
+
+/// Returns the (before, after) context sizes; -A/-B take precedence over -C.
+/// JSON, -o, and multiline output carry no context lines.
+fn context_lines(args: &Args) -> (usize, usize) {
+    if args.json || args.only_matching || args.vimgrep || args.multiline {
+        return (0, 0);
+    }
+    let both = args.context.unwrap_or(0);
+    (
+        args.before_context.unwrap_or(both),
+        args.after_context.unwrap_or(both),
+    )
+}
+
+/// Collects the positional keyword, every -e pattern, the non-empty lines
+/// of every -f file, and the escaped contents of every --literal-file;
+/// exits with code 2 if a pattern file cannot be read.
+fn collect_patterns(args: &Args) -> Vec<String> {
+    let mut patterns: Vec<String> = args.keyword.iter().chain(&args.regexp).cloned().collect();
+    for path in &args.pattern_files {
+        let contents = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read pattern file {}: {}", path.display(), e);
+            std::process::exit(2);
+        });
+        patterns.extend(
+            contents
+                .lines()
+                .map(|line| line.trim_end_matches('\r'))
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+    for path in &args.literal_files {
+        let contents = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read pattern file {}: {}", path.display(), e);
+            std::process::exit(2);
+        });
+        // The file's final newline ends the snippet rather than being part
+        // of it, so a snippet saved by an editor still matches mid-line.
+        let literal = contents.strip_suffix('\n').unwrap_or(&contents);
+        let literal = literal.strip_suffix('\r').unwrap_or(literal);
+        // -F escapes every pattern later, so escape here only without it.
+        patterns.push(if args.fixed_strings {
+            literal.to_string()
+        } else {
+            regex::escape(literal)
+        });
+    }
+    patterns
+}
+
+/// Resolves case sensitivity: explicit -i always wins; smart case only
+/// relaxes patterns without uppercase letters.
+fn ignores_case(args: &Args, keywords: &[String]) -> bool {
+    args.ignore_case
+        || (args.smart_case
+            && !keywords
+                .iter()
+                .any(|k| k.chars().any(|c| c.is_ascii_uppercase())))
+}
+
+/// Builds the matcher according to CLI flags, combining all collected
+/// patterns and --and patterns into a single alternation.
+fn build_matcher(args: &Args) -> Matcher {
+    let mut keywords = collect_patterns(args);
+    keywords.extend(args.and_patterns.iter().cloned());
+    compile_patterns(&keywords, ignores_case(args, &keywords), args)
+}
+
+/// Builds one regex per --and requirement: the main patterns, then each
+/// --and pattern. A file is searched only if all of them match in it.
+fn build_required(args: &Args) -> Vec<Matcher> {
+    if args.and_patterns.is_empty() {
+        return Vec::new();
+    }
+    let main = collect_patterns(args);
+    let all: Vec<String> = main.iter().chain(&args.and_patterns).cloned().collect();
+    // Case is resolved over every pattern, exactly as for the main matcher.
+    let ignore_case = ignores_case(args, &all);
+    std::iter::once(main)
+        .chain(args.and_patterns.iter().map(|p| vec![p.clone()]))
+        .map(|keywords| compile_patterns(&keywords, ignore_case, args))
+        .collect()
+}
+
+/// Prepares `pattern` for --ascii. The searched text is a `str`, so
+/// constructs that could match a lone non-ASCII byte are rejected with
+/// Unicode off; `.`, `\W`, `\D`, `\S`, and negated classes are rewritten to
+/// match whole characters instead, keeping their ASCII-only complements.
+fn ascii_pattern(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('W') => out.push_str("(?u:[^0-9A-Za-z_])"),
+                Some('D') => out.push_str("(?u:[^0-9])"),
+                Some('S') => out.push_str(r"(?u:[^\t\n\v\f\r ])"),
+                Some(escaped) => {
+                    out.push('\\');
+                    out.push(escaped);
+                }
+                None => out.push('\\'),
+            },
+            '.' => out.push_str("(?u:.)"),
+            '[' => {
+                // Copy the class verbatim, tracking nesting and escapes; a
+                // "]" right after the opening bracket (or "^") is literal.
+                let mut class = String::from("[");
+                let negated = chars.peek() == Some(&'^');
+                if negated {
+                    class.push(chars.next().unwrap_or('^'));
+                }
+                if chars.peek() == Some(&']') {
+                    class.push(chars.next().unwrap_or(']'));
+                }
+                let mut depth = 1;
+                while let Some(c) = chars.next() {
+                    class.push(c);
+                    match c {
+                        '\\' => class.extend(chars.next()),
+                        '[' => depth += 1,
+                        ']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if negated {
+                    out.push_str("(?u:");
+                    out.push_str(&class);
+                    out.push(')');
+                } else {
+                    out.push_str(&class);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Compiles `keywords` into one alternation, applying -F, -w, -x, and the
+/// regex flags; an empty list matches nothing. Exits with code 2 if the
+/// pattern is invalid.
+fn compile_patterns(keywords: &[String], ignore_case: bool, args: &Args) -> Matcher {
+    try_compile_patterns(keywords, ignore_case, args).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        std::process::exit(2);
+    })
+}
+
+/// Compiles `keywords` like `compile_patterns`, returning the message to
+/// report if the pattern is invalid.
+fn try_compile_patterns(
+    keywords: &[String],
+    ignore_case: bool,
+    args: &Args,
+) -> Result<Matcher, String> {
+    let patterns: Vec<String> = keywords
+        .iter()
+        .map(|keyword| {
+            let mut pattern = if args.fixed_strings {
+                regex::escape(keyword)
+            } else {
+                keyword.clone()
+            };
+            // Grouping keeps alternations inside the boundaries; \b is
+            // Unicode-aware unless --ascii, so "wörld" counts as one word.
+            if args.word_regexp {
+                pattern = format!(r"\b(?:{})\b", pattern);
+            }
+            if args.line_regexp {
+                pattern = format!("^(?:{})$", pattern);
+            }
+            pattern
+        })
+        .collect();
+    let pattern = match patterns.as_slice() {
+        // An empty pattern file matches nothing, like grep.
+        [] => NEVER_MATCHES.to_string(),
+        [single] => single.clone(),
+        _ => patterns
+            .iter()
+            .map(|p| format!("(?:{})", p))
+            .collect::<Vec<_>>()
+            .join("|"),
+    };
+    // Errors still quote the pattern as written, not the rewrite.
+    let source = if args.ascii {
+        ascii_pattern(&pattern)
+    } else {
+        pattern.clone()
+    };
+    let size_limit = usize::try_from(args.regex_size_limit).unwrap_or(usize::MAX);
+    let dfa_size_limit = usize::try_from(args.dfa_size_limit).unwrap_or(usize::MAX);
+    if args.engine == Engine::Fancy {
+        let compiled = fancy_regex::RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .unicode_mode(!args.ascii)
+            .multi_line(args.multiline)
+            .dot_matches_new_line(args.multiline)
+            .crlf(args.crlf)
+            .delegate_size_limit(size_limit)
+            .delegate_dfa_size_limit(dfa_size_limit)
+            .build()
+            .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+        return Ok(Matcher::Fancy(compiled));
+    }
+    let compiled = RegexBuilder::new(&source)
+        .case_insensitive(ignore_case)
+        .unicode(!args.ascii)
+        .multi_line(args.multiline)
+        .dot_matches_new_line(args.multiline)
+        .crlf(args.crlf)
+        .size_limit(size_limit)
+        .dfa_size_limit(dfa_size_limit)
+        .build()
+        .map_err(|e| match e {
+            regex::Error::CompiledTooBig(limit) => format!(
+                "Pattern '{}' exceeds the size limit of {} bytes (see --regex-size-limit)",
+                pattern, limit
+            ),
+            e => format!("Invalid pattern '{}': {}", pattern, e),
+        })?;
+    Ok(Matcher::Default(compiled))
+}
+
+/// A compiled search pattern from either engine. fancy-regex reports
+/// failures while matching (such as exceeding its backtracking limit); an
+/// input that fails counts as not matching there, and matching stops early.
+enum Matcher {
+    Default(Regex),
+    Fancy(fancy_regex::Regex),
+}
+
+/// One match of a [`Matcher`] in a text.
+#[derive(Clone, Copy)]
+struct Found<'t> {
+    matched: &'t str,
+    start: usize,
+}
+
+impl<'t> Found<'t> {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.matched.len()
+    }
+
+    fn as_str(&self) -> &'t str {
+        self.matched
+    }
+}
+
+impl<'t> From<regex::Match<'t>> for Found<'t> {
+    fn from(m: regex::Match<'t>) -> Self {
+        Found {
+            matched: m.as_str(),
+            start: m.start(),
+        }
+    }
+}
+
+impl<'t> From<fancy_regex::Match<'t>> for Found<'t> {
+    fn from(m: fancy_regex::Match<'t>) -> Self {
+        Found {
+            matched: m.as_str(),
+            start: m.start(),
+        }
+    }
+}
+
+/// The capture groups of one match of a [`Matcher`].
+enum Captures<'t> {
+    Default(regex::Captures<'t>),
+    Fancy(fancy_regex::Captures<'t, str>),
+}
+
+impl<'t> Captures<'t> {
+    /// Returns the whole match (group 0).
+    fn whole(&self) -> Found<'t> {
+        let found = match self {
+            Captures::Default(caps) => caps.get(0).map(Found::from),
+            Captures::Fancy(caps) => caps.get(0).map(Found::from),
+        };
+        found.expect("group 0 always participates")
+    }
+
+    /// Expands `$1`/`${name}` references in `replacement` into `dst`.
+    fn expand(&self, replacement: &str, dst: &mut String) {
+        match self {
+            Captures::Default(caps) => caps.expand(replacement, dst),
+            Captures::Fancy(caps) => caps.expand(replacement, dst),
+        }
+    }
+}
+
+impl Matcher {
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Default(re) => re.is_match(text),
+            Matcher::Fancy(re) => re.is_match(text).unwrap_or(false),
+        }
+    }
+
+    fn find<'t>(&self, text: &'t str) -> Option<Found<'t>> {
+        match self {
+            Matcher::Default(re) => re.find(text).map(Found::from),
+            Matcher::Fancy(re) => re.find(text).ok().flatten().map(Found::from),
+        }
+    }
+
+    fn find_iter<'t>(&'t self, text: &'t str) -> Box<dyn Iterator<Item = Found<'t>> + 't> {
+        match self {
+            Matcher::Default(re) => Box::new(re.find_iter(text).map(Found::from)),
+            Matcher::Fancy(re) => {
+                Box::new(re.find_iter(text).map_while(Result::ok).map(Found::from))
+            }
+        }
+    }
+
+    fn captures_iter<'t>(&'t self, text: &'t str) -> Box<dyn Iterator<Item = Captures<'t>> + 't> {
+        match self {
+            Matcher::Default(re) => Box::new(re.captures_iter(text).map(Captures::Default)),
+            Matcher::Fancy(re) => Box::new(
+                re.captures_iter(text)
+                    .map_while(Result::ok)
+                    .map(Captures::Fancy),
+            ),
+        }
+    }
+
+    /// Replaces every match in `text` with what `substitute` returns for it.
+    fn replace_with(&self, text: &str, mut substitute: impl FnMut(&Captures) -> String) -> String {
+        let mut replaced = String::with_capacity(text.len());
+        let mut last_end = 0;
+        for caps in self.captures_iter(text) {
+            let whole = caps.whole();
+            replaced.push_str(&text[last_end..whole.start()]);
+            replaced.push_str(&substitute(&caps));
+            last_end = whole.end();
+        }
+        replaced.push_str(&text[last_end..]);
+        replaced
+    }
+
+    /// Replaces every match in `text` with `replacement`, expanding group
+    /// references.
+    fn replace_all(&self, text: &str, replacement: &str) -> String {
+        self.replace_with(text, |caps| expand_replacement(caps, replacement))
+    }
+}
+
+/// Compiles the main patterns for scanning a whole memory-mapped file at
+/// once (see [`scan_mapped`]): `^` and `$` match at every line break, CRLF
+/// included, so every line that matches on its own also matches in the
+/// buffer. Only plain matching lines qualify — no context, -v, --unique,
+/// -U, -z, --and, or --encoding — and only with the default engine;
+/// patterns that still anchor to the whole input once parsed that way
+/// (`\A`, `\z`, or `^`/`$` under `(?-m)`) would miss lines, so they are
+/// read line by line too.
+fn build_scanner(re: &Matcher, args: &Args) -> Option<Regex> {
+    let Matcher::Default(re) = re else {
+        return None;
+    };
+    let (before, after) = context_lines(args);
+    let plain = !args.count
+        && !args.list_files
+        && !args.files_without_match
+        && before == 0
+        && after == 0
+        && !args.context_block
+        && !args.passthru
+        && args.file_tail.is_none()
+        && !args.invert_match
+        && !args.unique
+        && !args.unique_per_file
+        && !args.multiline
+        && !args.null_data
+        && args.and_patterns.is_empty()
+        && args.encoding.is_none();
+    if !plain || anchors_whole_input(re.as_str(), args) {
+        return None;
+    }
+    RegexBuilder::new(re.as_str())
+        .case_insensitive(ignores_case(args, &collect_patterns(args)))
+        .unicode(!args.ascii)
+        .multi_line(true)
+        .crlf(true)
+        .size_limit(usize::try_from(args.regex_size_limit).unwrap_or(usize::MAX))
+        .dfa_size_limit(usize::try_from(args.dfa_size_limit).unwrap_or(usize::MAX))
+        .build()
+        .ok()
+}
+
+/// Returns true if `pattern`, parsed in multi-line mode as the scanner
+/// compiles it, anchors to the start or end of the whole input; a pattern
+/// that does not parse is treated as anchored.
+fn anchors_whole_input(pattern: &str, args: &Args) -> bool {
+    regex_syntax::ParserBuilder::new()
+        .unicode(!args.ascii)
+        .multi_line(true)
+        .crlf(true)
+        .build()
+        .parse(pattern)
+        .map_or(true, |hir| {
+            hir.properties().look_set().contains_anchor_haystack()
+        })
+}
+
+/// Returns a substring finder when the search is a single case-sensitive
+/// literal, which every match must contain; files lacking it can then be
+/// skipped without splitting them into lines. Inverted searches,
+/// --passthru (which prints files without matches too), --encoding (where
+/// file bytes differ from the searched text), and --encoding-errors skip or
+/// strict (which must still see invalid UTF-8 in skipped files) never
+/// qualify.
+fn literal_prefilter(args: &Args) -> Option<Finder<'static>> {
+    if args.invert_match
+        || args.passthru
+        || args.encoding.is_some()
+        || args.encoding_errors != EncodingErrors::Lossy
+    {
+        return None;
+    }
+    let keywords = collect_patterns(args);
+    let [keyword] = keywords.as_slice() else {
+        return None;
+    };
+    let literal = args.fixed_strings || regex::escape(keyword) == *keyword;
+    let folded = ignores_case(args, &keywords) && keyword.chars().any(char::is_alphabetic);
+    // U+FFFD could match invalid bytes replaced while decoding lines.
+    if !literal || folded || keyword.is_empty() || keyword.contains('\u{FFFD}') {
+        return None;
+    }
+    Some(Finder::new(keyword.as_bytes()).into_owned())
+}
+
+/// Returns true if a prefilter is given and `haystack` lacks its literal.
+fn lacks_literal(prefilter: Option<&Finder>, haystack: &[u8]) -> bool {
+    prefilter.is_some_and(|finder| finder.find(haystack).is_none())
+}
+
+/// Paints text bold in `color`, or returns it unchanged when `None`.
+fn paint(text: &str, color: Option<Colour>) -> String {
+    match color {
+        Some(color) => color.bold().paint(text).to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Paints matched text like `paint`; with --show-whitespace, spaces and tabs
+/// in it are drawn as "·" and "→". Uncolored output is never altered.
+fn paint_match(text: &str, color: Option<Colour>, args: &Args) -> String {
+    if args.show_whitespace && color.is_some() {
+        paint(&text.replace(' ', "·").replace('\t', "→"), color)
+    } else {
+        paint(text, color)
+    }
+}
+
+/// Returns how many leading bytes of `text` --trim hides.
+fn trimmed_start(text: &str, args: &Args) -> usize {
+    if args.trim {
+        text.len() - text.trim_start().len()
+    } else {
+        0
+    }
+}
+
+/// Returns the byte range of `text` kept by --max-columns and the number of
+/// characters cut, or `None` if the line fits. With --max-columns-preview,
+/// the window starts at `first_match` when it would otherwise be cut off.
+fn column_window(
+    text: &str,
+    first_match: Option<&Range<usize>>,
+    args: &Args,
+) -> Option<(Range<usize>, usize)> {
+    let max = args.max_columns?;
+    let total = text.chars().count();
+    if total <= max {
+        return None;
+    }
+    let mut start = 0;
+    if let Some(mat) = first_match.filter(|_| args.max_columns_preview) {
+        if text[..mat.end].chars().count() > max {
+            start = text[..mat.start].chars().count().min(total - max);
+        }
+    }
+    let byte_at = |chars: usize| {
+        text.char_indices()
+            .nth(chars)
+            .map_or(text.len(), |(i, _)| i)
+    };
+    Some((byte_at(start)..byte_at(start + max), total - max))
+}
+
+/// Surrounds the shown part `body` of a line cut to `window` with ellipses
+/// where text was dropped, followed by the omitted-character count.
+fn mark_cut(text: &str, window: &Range<usize>, omitted: usize, body: &str) -> String {
+    let lead = if window.start > 0 { "…" } else { "" };
+    let tail = if window.end < text.len() { "…" } else { "" };
+    format!("{}{}{}[... {} omitted]", lead, body, tail, omitted)
+}
+
+/// Applies --trim and --max-columns to an unhighlighted line.
+fn fit_line(text: &str, args: &Args) -> String {
+    let visible = &text[trimmed_start(text, args)..];
+    match column_window(visible, None, args) {
+        Some((window, omitted)) => mark_cut(visible, &window, omitted, &visible[window.clone()]),
+        None => visible.to_string(),
+    }
+}
+
+/// Highlights all matches in a line bold in `color` when given, applying
+/// --trim and --max-columns. Matches are found in the whole line, so neither
+/// changes what counts as a match; only their shown positions move.
+fn highlight_line(text: &str, re: &Matcher, args: &Args, color: Option<Colour>) -> String {
+    let skip = trimmed_start(text, args);
+    let visible = &text[skip..];
+    let matches: Vec<Range<usize>> = re
+        .find_iter(text)
+        .map(|m| m.start().saturating_sub(skip)..m.end().saturating_sub(skip))
+        .filter(|m| !m.is_empty())
+        .collect();
+    let cut = column_window(visible, matches.first(), args);
+    let window = cut
+        .as_ref()
+        .map_or(0..visible.len(), |(window, _)| window.clone());
+    let mut body = String::new();
+    let mut last_end = window.start;
+    for mat in &matches {
+        let (start, end) = (mat.start.max(window.start), mat.end.min(window.end));
+        if start >= end {
+            continue;
+        }
+        body.push_str(&visible[last_end..start]);
+        body.push_str(&paint_match(&visible[start..end], color, args));
+        last_end = end;
+    }
+    body.push_str(&visible[last_end..window.end]);
+    match cut {
+        Some((window, omitted)) => mark_cut(visible, &window, omitted, &body),
+        None => body,
+    }
+}
+
+/// A line read from an input, without its terminator.
+#[derive(Clone)]
+struct Line {
+    number: usize,
+    /// Byte offset of the start of the line within the input.
+    offset: usize,
+    text: String,
+}
+
+/// Follows the blocks of a file for --context-block, by its extension.
+enum BlockTracker {
+    /// Python: open `def` and `class` lines with their indentation, closed
+    /// by the next line indented no deeper.
+    Indent(Vec<(usize, Line)>),
+    /// C-like languages: the line that opened each unclosed `{`, where a
+    /// lone `{` is opened by the line before it.
+    Braces {
+        open: Vec<Line>,
+        previous: Option<Line>,
+    },
+}
+
+impl BlockTracker {
+    /// Returns the tracker for the file shown as `label`, or `None` for
+    /// languages it does not know.
+    fn for_label(label: &str) -> Option<Self> {
+        let ext = Path::new(label).extension()?.to_str()?;
+        if ext == "py" {
+            Some(BlockTracker::Indent(Vec::new()))
+        } else if BRACE_LANGUAGES.contains(&ext) {
+            Some(BlockTracker::Braces {
+                open: Vec::new(),
+                previous: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the opener of the innermost block around `line`, which has
+    /// not been fed yet; control-flow blocks are skipped.
+    fn enclosing(&self, line: &Line) -> Option<&Line> {
+        match self {
+            BlockTracker::Indent(open) => {
+                let blank = line.text.trim().is_empty();
+                let indent = indentation(&line.text);
+                open.iter()
+                    .rev()
+                    .find(|(depth, _)| blank || *depth < indent)
+                    .map(|(_, opener)| opener)
+            }
+            BlockTracker::Braces { open, .. } => open
+                .iter()
+                .rev()
+                .find(|opener| !opens_control_block(&opener.text)),
+        }
+    }
+
+    /// Updates the open blocks with the next line of the file.
+    fn feed(&mut self, line: &Line) {
+        if line.text.trim().is_empty() {
+            return;
+        }
+        match self {
+            BlockTracker::Indent(open) => {
+                let indent = indentation(&line.text);
+                while open.last().is_some_and(|(depth, _)| *depth >= indent) {
+                    open.pop();
+                }
+                let code = line.text.trim_start();
+                if ["def ", "async def ", "class "]
+                    .iter()
+                    .any(|keyword| code.starts_with(keyword))
+                {
+                    open.push((indent, line.clone()));
+                }
+            }
+            BlockTracker::Braces { open, previous } => {
+                let opener = match previous {
+                    Some(previous) if line.text.trim() == "{" => previous,
+                    _ => line,
+                };
+                let mut pushed = Vec::new();
+                for c in code_braces(&line.text) {
+                    match c {
+                        '{' => pushed.push(opener.clone()),
+                        _ if pushed.pop().is_none() => {
+                            open.pop();
+                        }
+                        _ => {}
+                    }
+                }
+                open.extend(pushed);
+                *previous = Some(line.clone());
+            }
+        }
+    }
+}
+
+/// Returns the braces of a line of C-like code, skipping those in string
+/// literals and after a `//` comment.
+fn code_braces(text: &str) -> Vec<char> {
+    let mut braces = Vec::new();
+    let mut quote = None;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            // A quote is a lifetime in Rust unless it closes a char literal.
+            (None, '\'') if chars.peek() == Some(&'\\') => quote = Some(c),
+            (None, '\'') if chars.clone().nth(1) == Some('\'') => {
+                chars.nth(1);
+            }
+            (None, '"' | '`') => quote = Some(c),
+            (None, '/') if chars.peek() == Some(&'/') => break,
+            (None, '{' | '}') => braces.push(c),
+            (None, _) => {}
+        }
+    }
+    braces
+}
+
+/// Returns the width of the leading whitespace of `text`, in characters.
+fn indentation(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_whitespace()).count()
+}
+
+/// Returns true if `text` opens an `if`, loop, or other control-flow block
+/// rather than a function or type, including `} else {`.
+fn opens_control_block(text: &str) -> bool {
+    let code = text.trim_start_matches(|c: char| c == '}' || c.is_whitespace());
+    let word_end = code
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(code.len());
+    CONTROL_KEYWORDS.contains(&&code[..word_end])
+}
+
+/// Returns true if the start of the input contains a NUL byte, without
+/// consuming anything. With -z NUL is the line terminator, so nothing is
+/// binary.
+fn detect_binary<R: BufRead>(reader: &mut R, args: &Args) -> io::Result<bool> {
+    if args.null_data {
+        return Ok(false);
+    }
+    Ok(looks_binary(reader.fill_buf()?))
+}
+
+/// Returns true if `head`, the start of an input, holds a NUL byte within
+/// the first `BINARY_PEEK` bytes.
+fn looks_binary(head: &[u8]) -> bool {
+    head[..head.len().min(BINARY_PEEK)].contains(&0)
+}
+
+/// Returns true if invalid UTF-8 in an input starting with `head` must be
+/// rejected: --encoding-errors is skip or strict, and the input will be
+/// treated as text. Binary files are never decoded for display, so they
+/// are exempt unless -a is given.
+fn rejects_invalid_utf8(head: &[u8], args: &Args) -> bool {
+    args.encoding_errors != EncodingErrors::Lossy
+        && (args.text || args.null_data || !looks_binary(head))
+}
+
+/// Error payload marking input rejected by --encoding-errors skip/strict.
+#[derive(Debug)]
+struct InvalidUtf8(String);
+
+impl std::fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid UTF-8 {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+/// Returns true if `err` was raised for invalid UTF-8 under --encoding-errors.
+fn is_invalid_utf8(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<InvalidUtf8>())
+}
+
+/// Decodes `bytes` as UTF-8, replacing invalid sequences with U+FFFD unless
+/// `strict`, in which case they are an error located by `location`.
+fn decode_utf8(
+    bytes: &[u8],
+    strict: bool,
+    location: impl Fn(usize) -> String,
+) -> io::Result<String> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.to_string()),
+        Err(e) if strict => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            InvalidUtf8(location(e.valid_up_to())),
+        )),
+        Err(_) => Ok(String::from_utf8_lossy(bytes).into_owned()),
+    }
+}
+
+/// Splits an input into lines ending in `terminator`, tracking line numbers
+/// and byte offsets. Invalid UTF-8 is replaced with U+FFFD rather than
+/// aborting the input, unless --encoding-errors asks otherwise.
+struct LineReader<R> {
+    reader: R,
+    terminator: u8,
+    number: usize,
+    offset: usize,
+    /// Whether invalid UTF-8 is an error rather than replaced.
+    strict: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    /// Creates a reader splitting on NUL with -z and on newlines otherwise.
+    fn new(mut reader: R, args: &Args) -> Self {
+        // A read error here resurfaces on the first `next_line`.
+        let strict = reader
+            .fill_buf()
+            .is_ok_and(|head| rejects_invalid_utf8(head, args));
+        LineReader {
+            reader,
+            terminator: if args.null_data { b'\0' } else { b'\n' },
+            number: 0,
+            offset: 0,
+            strict,
+        }
+    }
+
+    /// Reads the next line, stripping its terminator (and a "\r" before a
+    /// newline, like `BufRead::lines`) and a UTF-8 BOM on the first line.
+    fn next_line(&mut self) -> io::Result<Option<Line>> {
+        let mut bytes = Vec::new();
+        let read = self.reader.read_until(self.terminator, &mut bytes)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if bytes.last() == Some(&self.terminator) {
+            bytes.pop();
+            if self.terminator == b'\n' && bytes.last() == Some(&b'\r') {
+                bytes.pop();
+            }
+        }
+        let bom = if self.number == 0 && bytes.starts_with(UTF8_BOM) {
+            UTF8_BOM.len()
+        } else {
+            0
+        };
+        self.number += 1;
+        let number = self.number;
+        let text = decode_utf8(&bytes[bom..], self.strict, |_| {
+            format!("on line {}", number)
+        })?;
+        let line = Line {
+            number: self.number,
+            offset: self.offset + bom,
+            text,
+        };
+        self.offset += read;
+        Ok(Some(line))
+    }
+}
+
+/// Expands `$1`/`${name}` references in `replacement` for one match.
+fn expand_replacement(caps: &Captures, replacement: &str) -> String {
+    let mut expanded = String::new();
+    caps.expand(replacement, &mut expanded);
+    expanded
+}
+
+/// Renders a substitution diff-style: the removed text struck through in
+/// red, followed by the inserted text in green.
+fn replacement_diff(removed: &str, inserted: &str) -> String {
+    format!(
+        "{}{}",
+        Colour::Red.strikethrough().paint(removed),
+        Colour::Green.bold().paint(inserted)
+    )
+}
+
+/// Replaces every match in a line with `replacement`; with `diff`, each
+/// substitution is shown diff-style so it can be checked before --in-place.
+fn replace_line(line: &str, re: &Matcher, replacement: &str, diff: bool) -> String {
+    re.replace_with(line, |caps| {
+        let inserted = expand_replacement(caps, replacement);
+        if diff {
+            replacement_diff(caps.whole().as_str(), &inserted)
+        } else {
+            inserted
+        }
+    })
+}
+
+/// Matching lines and individual matches found in one input. With -U every
+/// match counts as one line.
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    lines: usize,
+    matches: usize,
+}
+
+impl Counts {
+    /// Whether the input counts as a hit for the exit code: it matched, or
+    /// with -L it did not.
+    fn success(&self, args: &Args) -> bool {
+        (self.lines > 0) != args.files_without_match
+    }
+
+    /// The number -c prints: matches with --count-matches or --replace (every
+    /// match is one substitution), matching lines otherwise. Inverted lines
+    /// hold no matches to substitute, so -v always counts lines.
+    fn reported(&self, args: &Args) -> usize {
+        if args.count_matches || (args.replace.is_some() && !args.invert_match) {
+            self.matches
+        } else {
+            self.lines
+        }
+    }
+}
+
+/// Counts matching lines and matches of an input, stopping at -m matching
+/// lines if given; applies invert logic if requested (inverted lines hold
+/// no matches).
+fn count_matches<R: BufRead>(reader: R, re: &Matcher, args: &Args) -> io::Result<Counts> {
+    if args.multiline {
+        let contents = read_all(reader, args)?;
+        let limit = args.max_count.unwrap_or(usize::MAX);
+        let count = multiline_matches(&contents, re).take(limit).count();
+        return Ok(Counts {
+            lines: count,
+            matches: count,
+        });
+    }
+    let mut lines = LineReader::new(reader, args);
+    let mut counts = Counts::default();
+    while args.max_count.is_none_or(|max| counts.lines < max) {
+        let Some(line) = lines.next_line()? else {
+            break;
+        };
+        let is_match = re.is_match(&line.text);
+        if args.invert_match ^ is_match {
+            counts.lines += 1;
+            if is_match {
+                counts.matches += re.find_iter(&line.text).count();
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Returns true as soon as any line matches; used by quiet mode.
+fn has_match<R: BufRead>(reader: R, path: &Path, re: &Matcher, args: &Args) -> io::Result<bool> {
+    for_each_match(reader, path, re, args, &mut |_| ControlFlow::Break(()))
+        .map(|flow| flow.is_break())
+}
+
+/// Hands the matches in an input to `callback` as they are found, up to -m
+/// of them, and stops early when it returns `Break`. Returns `Break` if the
+/// callback stopped the search.
+fn for_each_match<R: BufRead>(
+    reader: R,
+    path: &Path,
+    re: &Matcher,
+    args: &Args,
+    callback: &mut dyn FnMut(&Match) -> ControlFlow<()>,
+) -> io::Result<ControlFlow<()>> {
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    if args.multiline {
+        let contents = read_all(reader, args)?;
+        for caps in multiline_matches(&contents, re).take(limit) {
+            let whole = caps.whole();
+            let start = contents[..whole.start()].rfind('\n').map_or(0, |i| i + 1);
+            let end = line_end(&contents, whole.end());
+            let found = Match {
+                path: path.to_path_buf(),
+                line_number: contents[..start].matches('\n').count() + 1,
+                text: contents[start..end].to_string(),
+                spans: std::iter::once(whole.start() - start..whole.end().min(end) - start)
+                    .collect(),
+            };
+            if callback(&found).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        return Ok(ControlFlow::Continue(()));
+    }
+    // The callback stopping the search is told apart from -m stopping it.
+    let mut stopped = false;
+    select_lines(reader, re, args, None, &mut |event| {
+        let LineEvent::Selected(line, _) = event else {
+            return Ok(match event {
+                LineEvent::PastLimit(_) => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            });
+        };
+        let spans = if args.invert_match {
+            Vec::new()
+        } else {
+            re.find_iter(&line.text)
+                .map(|m| m.start()..m.end())
+                .collect()
+        };
+        let found = Match {
+            path: path.to_path_buf(),
+            line_number: line.number,
+            text: line.text,
+            spans,
+        };
+        let flow = callback(&found);
+        stopped = flow.is_break();
+        Ok(flow)
+    })?;
+    Ok(if stopped {
+        ControlFlow::Break(())
+    } else {
+        ControlFlow::Continue(())
+    })
+}
+
+/// Adds every non-empty match in an input to `tally`.
+fn tally_matches<R: BufRead>(
+    reader: R,
+    re: &Matcher,
+    args: &Args,
+    tally: &mut HashMap<String, usize>,
+) -> io::Result<()> {
+    let mut add = |text: &str| {
+        for mat in re.find_iter(text).filter(|m| !m.as_str().is_empty()) {
+            *tally.entry(mat.as_str().to_string()).or_default() += 1;
+        }
+    };
+    if args.multiline {
+        add(&read_all(reader, args)?);
+        return Ok(());
+    }
+    let mut lines = LineReader::new(reader, args);
+    while let Some(line) = lines.next_line()? {
+        add(&line.text);
+    }
+    Ok(())
+}
+
+/// Prints a --tally, most frequent first and alphabetically among equals.
+fn print_tally<W: Write>(tally: HashMap<String, usize>, style: &OutputStyle, out: &mut W) {
+    let mut counted: Vec<(String, usize)> = tally.into_iter().collect();
+    counted.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    for (text, count) in counted {
+        let text = paint(&text, style.match_color);
+        if write!(out, "{}\t{}{}", count, text, style.eol).is_err() {
+            break;
+        }
+    }
+}
+
+/// Form in which paths are printed.
+#[derive(Debug)]
+enum PathForm {
+    AsGiven,
+    Absolute,
+    /// Relative to the working directory it holds.
+    Relative(PathBuf),
+}
+
+/// Lexically rewrites the absolute `path` relative to the absolute `base`,
+/// stepping out with ".." where they diverge.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative: PathBuf = base.components().skip(common).map(|_| "..").collect();
+    relative.extend(path.components().skip(common));
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// Output settings resolved once from flags and the terminal at startup.
+#[derive(Debug)]
+struct OutputStyle {
+    /// Colors of matches, line numbers, and file names; `None` when that
+    /// field is not colorized.
+    match_color: Option<Colour>,
+    line_color: Option<Colour>,
+    path_color: Option<Colour>,
+    /// Whether file names are printed in front of output lines.
+    filename: bool,
+    /// Whether line numbers are printed in front of output lines.
+    line_number: bool,
+    /// Whether matching lines are grouped under a per-file heading.
+    heading: bool,
+    /// Whether file names are followed by a NUL byte (-Z).
+    null: bool,
+    /// Replacement for the platform's path separator in printed paths.
+    path_separator: Option<char>,
+    /// How printed paths are rewritten before display.
+    path_form: PathForm,
+    /// Terminator of output records: NUL with -z, otherwise a newline.
+    eol: char,
+    /// Separators for context output, already painted when colorized.
+    group_separator: String,
+    context_separator: String,
+    /// Separator between the fields of matches and counts.
+    field_separator: String,
+    /// Matching lines printed so far, shared by every file with --unique.
+    seen: Option<Mutex<HashSet<String>>>,
+}
+
+impl OutputStyle {
+    /// Returns `path` as printed in output, honoring --absolute-path,
+    /// --relative-path, and --path-separator.
+    fn label(&self, path: &Path) -> String {
+        let label = match &self.path_form {
+            PathForm::AsGiven => path.display().to_string(),
+            PathForm::Absolute => fs::canonicalize(path)
+                .unwrap_or_else(|_| path.to_path_buf())
+                .display()
+                .to_string(),
+            PathForm::Relative(cwd) => relative_to(&cwd.join(path), cwd).display().to_string(),
+        };
+        match self.path_separator {
+            Some(sep) => label.replace(MAIN_SEPARATOR, sep.encode_utf8(&mut [0; 4])),
+            None => label,
+        }
+    }
+
+    /// Returns what follows a file name: NUL with -Z, otherwise `sep`.
+    fn after_path(&self, sep: char) -> char {
+        if self.null {
+            '\0'
+        } else {
+            sep
+        }
+    }
+
+    /// Writes what separates the output of two files: a blank line under
+    /// headings, the group separator with context, otherwise nothing.
+    fn write_file_break<W: Write>(&self, out: &mut W, with_context: bool) -> io::Result<()> {
+        if self.heading {
+            write!(out, "{}", self.eol)
+        } else if with_context {
+            write!(out, "{}{}", self.group_separator, self.eol)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Paints a file name in the path color, with any matches of
+    /// `highlight` in it shown in the match color instead.
+    fn paint_path(&self, label: &str, highlight: Option<&Matcher>) -> String {
+        let Some(re) = highlight.filter(|_| self.match_color.is_some()) else {
+            return paint(label, self.path_color);
+        };
+        let mut painted = String::new();
+        let mut last_end = 0;
+        for mat in re.find_iter(label).filter(|m| !m.as_str().is_empty()) {
+            if mat.start() > last_end {
+                painted.push_str(&paint(&label[last_end..mat.start()], self.path_color));
+            }
+            painted.push_str(&paint(mat.as_str(), self.match_color));
+            last_end = mat.end();
+        }
+        if last_end < label.len() {
+            painted.push_str(&paint(&label[last_end..], self.path_color));
+        }
+        painted
+    }
+
+    /// Formats the `path:line:` prefix of an output line, including the
+    /// column with --column and the byte offset with -b; `sep` is the field
+    /// separator for matches and the context separator for context lines.
+    /// The path is omitted when `label` is `None`, the line number when line
+    /// numbers are off.
+    fn line_prefix(
+        &self,
+        label: Option<&str>,
+        line_number: usize,
+        column: Option<usize>,
+        byte_offset: Option<usize>,
+        sep: &str,
+    ) -> String {
+        let mut prefix = String::new();
+        if let Some(label) = label {
+            prefix.push_str(&self.paint_path(label, None));
+            if self.null {
+                prefix.push('\0');
+            } else {
+                prefix.push_str(sep);
+            }
+        }
+        if self.line_number {
+            prefix.push_str(&paint(&line_number.to_string(), self.line_color));
+            prefix.push_str(sep);
+        }
+        for field in [column, byte_offset].into_iter().flatten() {
+            prefix.push_str(&format!("{}{}", field, sep));
+        }
+        prefix
+    }
+}
+
+/// Returns the 1-based character column of byte index `start` in `text`.
+fn char_column(text: &str, start: usize) -> usize {
+    text[..start].chars().count() + 1
+}
+
+/// What [`select_lines`] found for one line of an input.
+enum LineEvent {
+    /// A line selected by the patterns (or not matched, with -v), and
+    /// whether the patterns matched it.
+    Selected(Line, bool),
+    /// A line that was not selected.
+    Unselected(Line),
+    /// A line read after -m lines were selected.
+    PastLimit(Line),
+}
+
+/// An input as [`print_matches`] reads it.
+enum LineSource<'m, R> {
+    /// Read line by line, as [`select_lines`] does.
+    Reader(R),
+    /// The text of a memory-mapped file, found `offset` bytes into it (past
+    /// a BOM), scanned as a whole by [`scan_mapped`].
+    Mapped {
+        text: &'m str,
+        offset: usize,
+        scanner: &'m Regex,
+    },
+}
+
+/// Selects the matching lines of `text`, a memory-mapped file's contents
+/// starting `offset` bytes into it, without splitting it into lines: the
+/// scanner finds the next candidate in the whole buffer, and only the line
+/// holding it is checked against `re` and copied out. Line numbers are
+/// counted only up to each selected line. Unselected lines are never
+/// reported, so this only serves output without context.
+fn scan_mapped(
+    text: &str,
+    offset: usize,
+    scanner: &Regex,
+    re: &Matcher,
+    args: &Args,
+    on_line: &mut dyn FnMut(LineEvent) -> io::Result<ControlFlow<()>>,
+) -> io::Result<()> {
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    let bytes = text.as_bytes();
+    let mut selected = 0;
+    // Line `number` starts at byte `counted`.
+    let mut number = 1;
+    let mut counted = 0;
+    let mut pos = 0;
+    while selected < limit && pos < text.len() {
+        let Some(found) = scanner.find_at(text, pos) else {
+            break;
+        };
+        let start = memchr::memrchr(b'\n', &bytes[pos..found.start()]).map_or(pos, |i| pos + i + 1);
+        let end = memchr::memchr(b'\n', &bytes[found.start()..])
+            .map_or(text.len(), |i| found.start() + i);
+        pos = end + 1;
+        if start == text.len() {
+            // A trailing line break ends the last line; no line follows it.
+            break;
+        }
+        // Like `LineReader`, drop the "\r" of a CRLF line break.
+        let line = &text[start..end];
+        let line = match line.strip_suffix('\r') {
+            Some(stripped) if end < text.len() => stripped,
+            _ => line,
+        };
+        if !re.is_match(line) {
+            continue;
+        }
+        number += memchr::memchr_iter(b'\n', &bytes[counted..start]).count();
+        counted = start;
+        selected += 1;
+        let line = Line {
+            number,
+            offset: offset + start,
+            text: line.to_string(),
+        };
+        if on_line(LineEvent::Selected(line, true))?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads an input line by line, selects lines by the patterns, -v, -m, and
+/// --unique (whose lines are tracked in `seen` across files), and hands
+/// every line to `on_line` until it returns `Break`. This is the loop behind
+/// both the printed output and [`Searcher::search_with`].
+fn select_lines<R: BufRead>(
+    reader: R,
+    re: &Matcher,
+    args: &Args,
+    seen: Option<&Mutex<HashSet<String>>>,
+    on_line: &mut dyn FnMut(LineEvent) -> io::Result<ControlFlow<()>>,
+) -> io::Result<()> {
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    let mut selected = 0;
+    let mut lines = LineReader::new(reader, args);
+    let mut file_seen = HashSet::new();
+    // A repeated line is treated as not matching, so it can still show up
+    // as context around another match.
+    let mut first_seen = |text: &str| match seen {
+        Some(seen) => seen
+            .lock()
+            .expect("seen set poisoned")
+            .insert(text.to_string()),
+        None => !args.unique_per_file || file_seen.insert(text.to_string()),
+    };
+    while let Some(line) = lines.next_line()? {
+        let event = if selected >= limit {
+            LineEvent::PastLimit(line)
+        } else {
+            let is_match = re.is_match(&line.text);
+            if args.invert_match ^ is_match && first_seen(&line.text) {
+                selected += 1;
+                LineEvent::Selected(line, is_match)
+            } else {
+                LineEvent::Unselected(line)
+            }
+        };
+        if on_line(event)?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes matching lines of an input labelled `label` to `out` with
+/// highlighting and surrounding context; returns what was matched.
+fn print_matches<R: BufRead, W: Write>(
+    input: LineSource<R>,
+    label: &str,
+    re: &Matcher,
+    args: &Args,
+    style: &OutputStyle,
+    out: &mut W,
+) -> io::Result<Counts> {
+    let (before, after) = context_lines(args);
+    let with_context = before > 0 || after > 0 || args.context_block;
+    let offset_of = |offset: usize| args.byte_offset.then_some(offset);
+    let prefix_label = (style.filename && !style.heading).then_some(label);
+    let mut pending: VecDeque<Line> = VecDeque::with_capacity(before);
+    let tail_len = args.file_tail.unwrap_or(0);
+    let mut tail: VecDeque<Line> = VecDeque::with_capacity(tail_len);
+    let mut last_printed: Option<usize> = None;
+    let mut after_left = 0;
+    let mut counts = Counts::default();
+    let mut blocks = args
+        .context_block
+        .then(|| BlockTracker::for_label(label))
+        .flatten();
+    let mut on_line = |event: LineEvent| -> io::Result<ControlFlow<()>> {
+        // Past -m, only the trailing context of the last match is still
+        // printed, though --file-tail needs the rest of the file.
+        let (line, selected, is_match) = match event {
+            LineEvent::PastLimit(_) if after_left == 0 && !args.passthru && tail_len == 0 => {
+                return Ok(ControlFlow::Break(()));
+            }
+            LineEvent::Selected(line, is_match) => (line, true, is_match),
+            LineEvent::Unselected(line) | LineEvent::PastLimit(line) => (line, false, false),
+        };
+        if tail_len > 0 {
+            if tail.len() == tail_len {
+                tail.pop_front();
+            }
+            tail.push_back(line.clone());
+        }
+        let block = match &mut blocks {
+            Some(blocks) => {
+                let block = selected.then(|| blocks.enclosing(&line).cloned()).flatten();
+                blocks.feed(&line);
+                block
+            }
+            None => None,
+        };
+        if selected {
+            let group_start = pending.front().map_or(line.number, |l| l.number);
+            let new_group = last_printed.is_some_and(|last| group_start > last + 1);
+            if style.heading && last_printed.is_none() {
+                let heading = style.paint_path(label, None);
+                write!(out, "{}{}", heading, style.after_path(style.eol))?;
+            }
+            if with_context && new_group {
+                write!(out, "{}{}", style.group_separator, style.eol)?;
+            }
+            // The block line heads each group it is not already part of.
+            let heads_group = last_printed.is_none() || new_group;
+            if let Some(block) = block.filter(|b| heads_group && b.number < group_start) {
+                let mut prefix = style.line_prefix(
+                    prefix_label,
+                    block.number,
+                    None,
+                    offset_of(block.offset),
+                    "=",
+                );
+                if prefix.is_empty() {
+                    prefix.push('=');
+                }
+                write!(
+                    out,
+                    "{}{}{}",
+                    prefix,
+                    fit_line(&block.text, args),
+                    style.eol
+                )?;
+            }
+            for context in pending.drain(..) {
+                let prefix = style.line_prefix(
+                    prefix_label,
+                    context.number,
+                    None,
+                    offset_of(context.offset),
+                    &style.context_separator,
+                );
+                write!(
+                    out,
+                    "{}{}{}",
+                    prefix,
+                    fit_line(&context.text, args),
+                    style.eol
+                )?;
+            }
+            counts.lines += 1;
+            if is_match {
+                counts.matches += re.find_iter(&line.text).count();
+            }
+            if args.json {
+                let spans: Vec<_> = re
+                    .find_iter(&line.text)
+                    .map(|m| json!({"start": m.start(), "end": m.end()}))
+                    .collect();
+                let mut record = json!({
+                    "type": "match",
+                    "path": label,
+                    "line_number": line.number,
+                    "text": line.text,
+                    "matches": spans,
+                });
+                if let Some(replacement) = &args.replace {
+                    record["replacement"] = json!(replace_line(&line.text, re, replacement, false));
+                }
+                writeln!(out, "{}", record)?;
+            } else if args.only_matching {
+                for caps in re.captures_iter(&line.text) {
+                    let mat = caps.whole();
+                    if mat.as_str().is_empty() {
+                        continue;
+                    }
+                    let shown = match &args.replace {
+                        Some(replacement) => expand_replacement(&caps, replacement),
+                        None => mat.as_str().to_string(),
+                    };
+                    let column = args.column.then(|| char_column(&line.text, mat.start()));
+                    let prefix = style.line_prefix(
+                        prefix_label,
+                        line.number,
+                        column,
+                        offset_of(line.offset + mat.start()),
+                        &style.field_separator,
+                    );
+                    let shown = paint_match(&shown, style.match_color, args);
+                    write!(out, "{}{}{}", prefix, shown, style.eol)?;
+                }
+            } else if args.vimgrep {
+                let text = match &args.replace {
+                    Some(replacement) => replace_line(&line.text, re, replacement, false),
+                    None => line.text.clone(),
+                };
+                for mat in re.find_iter(&line.text) {
+                    let column = Some(char_column(&line.text, mat.start()));
+                    let offset = offset_of(line.offset + mat.start());
+                    let prefix = style.line_prefix(
+                        prefix_label,
+                        line.number,
+                        column,
+                        offset,
+                        &style.field_separator,
+                    );
+                    write!(out, "{}{}{}", prefix, text, style.eol)?;
+                }
+            } else {
+                // Inverted lines have no match, so their column is the line start.
+                // Columns count from where --trim starts the shown text.
+                let column = args.column.then(|| {
+                    let skip = trimmed_start(&line.text, args);
+                    re.find(&line.text).map_or(1, |m| {
+                        char_column(&line.text[skip..], m.start().saturating_sub(skip))
+                    })
+                });
+                let prefix = style.line_prefix(
+                    prefix_label,
+                    line.number,
+                    column,
+                    offset_of(line.offset),
+                    &style.field_separator,
+                );
+                let text = match &args.replace {
+                    // Painted text cannot be cut safely, so a cut
+                    // replacement is shown without colors.
+                    Some(replacement) if args.max_columns.is_some() => {
+                        fit_line(&replace_line(&line.text, re, replacement, false), args)
+                    }
+                    Some(replacement) => {
+                        let diff = style.match_color.is_some();
+                        let replaced = replace_line(&line.text, re, replacement, diff);
+                        if args.trim {
+                            replaced.trim_start().to_string()
+                        } else {
+                            replaced
+                        }
+                    }
+                    None => highlight_line(&line.text, re, args, style.match_color),
+                };
+                write!(out, "{}{}{}", prefix, text, style.eol)?;
+            }
+            last_printed = Some(line.number);
+            after_left = after;
+        } else if after_left > 0 || args.passthru {
+            if style.heading && last_printed.is_none() {
+                let heading = style.paint_path(label, None);
+                write!(out, "{}{}", heading, style.after_path(style.eol))?;
+            }
+            let prefix = style.line_prefix(
+                prefix_label,
+                line.number,
+                None,
+                offset_of(line.offset),
+                &style.context_separator,
+            );
+            write!(out, "{}{}{}", prefix, fit_line(&line.text, args), style.eol)?;
+            last_printed = Some(line.number);
+            after_left = after_left.saturating_sub(1);
+        } else if before > 0 {
+            if pending.len() == before {
+                pending.pop_front();
+            }
+            pending.push_back(line);
+        }
+        // Stop reading as soon as nothing more can be printed, so -m ends
+        // a search of a pipe at its last match.
+        let limit_reached = args.max_count.is_some_and(|max| counts.lines >= max);
+        let done = limit_reached && after_left == 0 && !args.passthru && tail_len == 0;
+        Ok(if done {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        })
+    };
+    match input {
+        LineSource::Reader(reader) => {
+            select_lines(reader, re, args, style.seen.as_ref(), &mut on_line)?
+        }
+        LineSource::Mapped {
+            text,
+            offset,
+            scanner,
+        } => scan_mapped(text, offset, scanner, re, args, &mut on_line)?,
+    }
+    if let Some(last) = last_printed {
+        let mut unprinted = tail.into_iter().filter(|l| l.number > last).peekable();
+        if unprinted.peek().is_some_and(|l| l.number > last + 1) {
+            write!(out, "{}{}", style.group_separator, style.eol)?;
+        }
+        for line in unprinted {
+            let prefix = style.line_prefix(
+                prefix_label,
+                line.number,
+                None,
+                offset_of(line.offset),
+                &style.context_separator,
+            );
+            write!(out, "{}{}{}", prefix, fit_line(&line.text, args), style.eol)?;
+        }
+    }
+    Ok(counts)
+}
+
+/// Reads a whole input into memory for multiline matching, handling
+/// invalid UTF-8 like `LineReader`.
+fn read_all<R: BufRead>(mut reader: R, args: &Args) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(&bytes);
+    let strict = rejects_invalid_utf8(bytes, args);
+    decode_utf8(bytes, strict, |at| format!("at byte {}", at))
+}
+
+/// Iterates over the non-empty matches of a whole-input search.
+fn multiline_matches<'c>(contents: &'c str, re: &'c Matcher) -> impl Iterator<Item = Captures<'c>> {
+    re.captures_iter(contents)
+        .filter(|caps| !caps.whole().as_str().is_empty())
+}
+
+/// Returns the index of the newline ending the line that contains the last
+/// byte before `end` (or the end of `contents`), so a match ending in a
+/// newline does not pull in the following line.
+fn line_end(contents: &str, end: usize) -> usize {
+    let end = if contents[..end].ends_with('\n') {
+        end - 1
+    } else {
+        end
+    };
+    contents[end..]
+        .find('\n')
+        .map_or(contents.len(), |i| end + i)
+}
+
+/// Writes each multiline match of `contents` with the whole lines it spans,
+/// numbered from the line where it starts; matches sharing a line are printed
+/// as one block. Returns what was matched.
+fn print_multiline<W: Write>(
+    contents: &str,
+    label: &str,
+    re: &Matcher,
+    args: &Args,
+    style: &OutputStyle,
+    out: &mut W,
+) -> io::Result<Counts> {
+    let prefix_label = (style.filename && !style.heading).then_some(label);
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    let mut matches = multiline_matches(contents, re).take(limit).peekable();
+    let mut line_number = 1;
+    let mut counted_to = 0;
+    let mut found = 0;
+    while let Some(first) = matches.next() {
+        let first_match = first.whole();
+        let start = contents[..first_match.start()]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let mut end = line_end(contents, first_match.end());
+        let mut block_matches = vec![first];
+        while let Some(next) = matches.next_if(|caps| caps.whole().start() <= end) {
+            let next_match = next.whole();
+            end = end.max(line_end(contents, next_match.end()));
+            block_matches.push(next);
+        }
+        line_number += contents[counted_to..start].matches('\n').count();
+        counted_to = start;
+        let block = &contents[start..end];
+
+        if style.heading && found == 0 {
+            let heading = style.paint_path(label, None);
+            write!(out, "{}{}", heading, style.after_path(style.eol))?;
+        }
+        found += block_matches.len();
+        // Match spans relative to the block, which excludes a final newline.
+        let spans: Vec<(usize, usize)> = block_matches
+            .iter()
+            .map(Captures::whole)
+            .map(|m| (m.start() - start, m.end().min(end) - start))
+            .collect();
+        if args.json {
+            let json_spans: Vec<_> = spans
+                .iter()
+                .map(|&(s, e)| json!({"start": s, "end": e}))
+                .collect();
+            let mut record = json!({
+                "type": "match",
+                "path": label,
+                "line_number": line_number,
+                "text": block,
+                "matches": json_spans,
+            });
+            if let Some(replacement) = &args.replace {
+                let mut replaced = String::new();
+                let mut last_end = 0;
+                for (caps, &(s, e)) in block_matches.iter().zip(&spans) {
+                    replaced.push_str(&block[last_end..s]);
+                    replaced.push_str(&expand_replacement(caps, replacement));
+                    last_end = e;
+                }
+                replaced.push_str(&block[last_end..]);
+                record["replacement"] = json!(replaced);
+            }
+            writeln!(out, "{}", record)?;
+        } else if args.only_matching {
+            for (caps, &(s, e)) in block_matches.iter().zip(&spans) {
+                let shown = match &args.replace {
+                    Some(replacement) => expand_replacement(caps, replacement),
+                    None => block[s..e].to_string(),
+                };
+                let number = line_number + block[..s].matches('\n').count();
+                let column = args.column.then(|| {
+                    let line_start = block[..s].rfind('\n').map_or(0, |i| i + 1);
+                    char_column(&block[line_start..], s - line_start)
+                });
+                let offset = args.byte_offset.then_some(start + s);
+                let prefix =
+                    style.line_prefix(prefix_label, number, column, offset, &style.field_separator);
+                let shown = paint_match(&shown, style.match_color, args);
+                write!(out, "{}{}{}", prefix, shown, style.eol)?;
+            }
+        } else {
+            let column = args.column.then(|| char_column(block, spans[0].0));
+            let offset = args.byte_offset.then_some(start);
+            let prefix = style.line_prefix(
+                prefix_label,
+                line_number,
+                column,
+                offset,
+                &style.field_separator,
+            );
+            let mut text = String::new();
+            let mut last_end = 0;
+            for (caps, &(s, e)) in block_matches.iter().zip(&spans) {
+                text.push_str(&block[last_end..s]);
+                let shown = match &args.replace {
+                    Some(replacement) if style.match_color.is_some() => {
+                        replacement_diff(&block[s..e], &expand_replacement(caps, replacement))
+                    }
+                    Some(replacement) => expand_replacement(caps, replacement),
+                    None => paint_match(&block[s..e], style.match_color, args),
+                };
+                text.push_str(&shown);
+                last_end = e;
+            }
+            text.push_str(&block[last_end..]);
+            if args.crlf {
+                text = text.replace("\r\n", "\n");
+                text.truncate(text.trim_end_matches('\r').len());
+            }
+            write!(out, "{}{}{}", prefix, text, style.eol)?;
+        }
+    }
+    Ok(Counts {
+        lines: found,
+        matches: found,
+    })
+}
+
+/// Runs the selected output mode over one input, writing results to `out`;
+/// returns what was matched.
+fn search_reader<R: BufRead, W: Write>(
+    reader: R,
+    label: &str,
+    re: &Matcher,
+    args: &Args,
+    style: &OutputStyle,
+    out: &mut W,
+) -> io::Result<Counts> {
+    let mut reader = reader;
+    let line_output = !args.count && !args.list_files && !args.files_without_match;
+    if line_output && !args.text && detect_binary(&mut reader, args)? {
+        // Like grep, binary files only report whether they matched.
+        let counts = count_matches(reader, re, args)?;
+        if counts.lines > 0 && args.json {
+            writeln!(
+                out,
+                "{}",
+                json!({"type": "binary", "path": label, "binary": true})
+            )?;
+        } else if counts.lines > 0 {
+            let label = style.paint_path(label, None);
+            write!(out, "Binary file {} matches{}", label, style.eol)?;
+        }
+        return Ok(counts);
+    }
+    if args.count {
+        let counts = count_matches(reader, re, args)?;
+        let count = counts.reported(args);
+        let shown = count > 0 || args.include_zero;
+        if shown && args.json {
+            writeln!(
+                out,
+                "{}",
+                json!({"type": "count", "path": label, "count": count})
+            )?;
+        } else if shown && style.filename {
+            write!(
+                out,
+                "{}{}{}{}",
+                style.paint_path(label, None),
+                if style.null {
+                    "\0"
+                } else {
+                    &style.field_separator
+                },
+                paint(&count.to_string(), style.match_color),
+                style.eol
+            )?;
+        } else if shown {
+            let count = paint(&count.to_string(), style.match_color);
+            write!(out, "{}{}", count, style.eol)?;
+        }
+        Ok(counts)
+    } else if args.list_files || args.files_without_match {
+        let counts = count_matches(reader, re, args)?;
+        if counts.success(args) && args.json {
+            writeln!(out, "{}", json!({"type": "file", "path": label}))?;
+        } else if counts.success(args) {
+            let label = style.paint_path(label, Some(re));
+            write!(out, "{}{}", label, style.after_path(style.eol))?;
+        }
+        Ok(counts)
+    } else if args.multiline {
+        print_multiline(&read_all(reader, args)?, label, re, args, style, out)
+    } else {
+        print_matches(LineSource::Reader(reader), label, re, args, style, out)
+    }
+}
+
+/// Applies --replace to the matching lines of `contents`, up to -m of them.
+fn replace_lines(contents: &str, re: &Matcher, args: &Args, replacement: &str) -> String {
+    let terminator = if args.null_data { '\0' } else { '\n' };
+    let mut rewritten = String::with_capacity(contents.len());
+    let mut replaced = 0;
+    for line in contents.split_inclusive(terminator) {
+        let mut body = line.strip_suffix(terminator).unwrap_or(line);
+        if terminator == '\n' {
+            body = body.strip_suffix('\r').unwrap_or(body);
+        }
+        if args.max_count.is_none_or(|max| replaced < max) && re.is_match(body) {
+            rewritten.push_str(&re.replace_all(body, replacement));
+            replaced += 1;
+        } else {
+            rewritten.push_str(body);
+        }
+        rewritten.push_str(&line[body.len()..]);
+    }
+    rewritten
+}
+
+/// Applies --replace to the matches in `path` (line by line unless -U) and
+/// atomically replaces the file via a temporary file in the same directory,
+/// first copying the original to `path.SUFFIX` when a suffix is given.
+fn rewrite_file(
+    path: &Path,
+    re: &Matcher,
+    args: &Args,
+    replacement: &str,
+    suffix: &str,
+) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    if !args.text && !args.null_data && contents.contains('\0') {
+        // Binary files are reported, never rewritten.
+        return Ok(());
+    }
+    // A byte-order mark is not searched, but is kept in the rewritten file.
+    let (bom, contents) = match contents.strip_prefix('\u{FEFF}') {
+        Some(rest) => ("\u{FEFF}", rest),
+        None => ("", contents.as_str()),
+    };
+    let rewritten = if args.multiline {
+        re.replace_all(contents, replacement)
+    } else {
+        replace_lines(contents, re, args, replacement)
+    };
+
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(bom.as_bytes())?;
+    temp.write_all(rewritten.as_bytes())?;
+    temp.as_file()
+        .set_permissions(fs::metadata(path)?.permissions())?;
+    let suffix = suffix.trim_start_matches('.');
+    if !suffix.is_empty() {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".");
+        backup.push(suffix);
+        fs::copy(path, backup)?;
+    }
+    temp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Returns true if `path` has a .gz extension.
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Archive formats searched entry by entry with --search-archives.
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// Returns the archive format `path` is named as, if any.
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Boxes a buffered input, transcoding it to UTF-8 when --encoding is given.
+fn decode_input<R: BufRead + 'static>(
+    mut reader: R,
+    label: Option<&str>,
+    args: &Args,
+) -> Box<dyn BufRead> {
+    let encoding = match args.encoding {
+        None => return Box::new(reader),
+        Some(InputEncoding::Auto) => None,
+        Some(InputEncoding::Detect) => detect_encoding(&mut reader, label, args),
+        Some(InputEncoding::Fixed(encoding)) => Some(encoding),
+    };
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(encoding)
+        .utf8_passthru(true)
+        .build(reader);
+    Box::new(BufReader::new(decoder))
+}
+
+/// Guesses an input's encoding from its start for --encoding auto-detect,
+/// without consuming anything. `None` leaves the input to BOM sniffing and
+/// UTF-8, which is also the fallback when the start holds too few non-ASCII
+/// bytes for a confident guess. With --stats the outcome is reported under
+/// `label`, which is `None` when the input is opened a second time.
+fn detect_encoding<R: BufRead>(
+    reader: &mut R,
+    label: Option<&str>,
+    args: &Args,
+) -> Option<&'static Encoding> {
+    let head = reader.fill_buf().ok()?;
+    let head = &head[..head.len().min(DETECT_PEEK)];
+    let has_bom = [UTF8_BOM, b"\xFF\xFE", b"\xFE\xFF"]
+        .iter()
+        .any(|bom| head.starts_with(bom));
+    let non_ascii = head.iter().filter(|b| !b.is_ascii()).count();
+    if has_bom || non_ascii == 0 {
+        return None;
+    }
+    let guess = (non_ascii >= DETECT_MIN_NON_ASCII).then(|| {
+        let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+        detector.feed(head, false);
+        detector.guess(None, Utf8Detection::Allow)
+    });
+    if let Some(label) = label.filter(|_| args.stats) {
+        match guess {
+            Some(encoding) => eprintln!("Detected {} in {}", encoding.name(), label),
+            None => eprintln!(
+                "Read {} as UTF-8: too little non-ASCII text to detect",
+                label
+            ),
+        }
+    }
+    guess.filter(|&encoding| encoding != encoding_rs::UTF_8)
+}
+
+/// Memory-maps `file` with --mmap, or by default when it is at least
+/// `MMAP_THRESHOLD` bytes; `None` means it should be read normally.
+fn map_file(file: &File, args: &Args) -> Option<Mmap> {
+    if args.no_mmap {
+        return None;
+    }
+    let len = file.metadata().ok()?.len();
+    if len == 0 || (!args.mmap && len < MMAP_THRESHOLD) {
+        return None;
+    }
+    // SAFETY: the map is only ever read. As with other grep tools, a file
+    // truncated by another process mid-search may fault; that is the price
+    // of skipping the copy through a read buffer.
+    unsafe { Mmap::map(file) }.ok()
+}
+
+/// An external command whose output is searched in place of a file (--pre).
+struct Preprocessor {
+    command: String,
+    /// Files the command applies to; `None` means every file.
+    globs: Option<GlobSet>,
+}
+
+impl Preprocessor {
+    fn applies(&self, path: &Path) -> bool {
+        self.globs
+            .as_ref()
+            .is_none_or(|globs| glob_matches(globs, path))
+    }
+
+    /// Runs `command path` and returns its standard output, failing if the
+    /// command cannot start or exits unsuccessfully.
+    fn run(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let output = Command::new(&self.command)
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.command, e)))?;
+        if !output.status.success() {
+            let mut message = format!("{} failed with {}", self.command, output.status);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if !stderr.trim().is_empty() {
+                message = format!("{}: {}", message, stderr.trim_end());
+            }
+            return Err(io::Error::other(message));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Opens files for searching, resolved once from the flags: preprocesses
+/// with --pre, decompresses .gz files with --search-zip, and memory-maps
+/// large files, either to scan them whole or to split lines straight from
+/// the mapping.
+struct FileOpener {
+    /// The patterns compiled for scanning mapped files as a whole, when the
+    /// output allows it.
+    scanner: Option<Regex>,
+    /// A literal every match contains; files without it open as empty input.
+    prefilter: Option<Finder<'static>>,
+    /// --and requirements; files where any of them never matches open as
+    /// empty input.
+    required: Vec<Matcher>,
+    preprocessor: Option<Preprocessor>,
+    /// With --search-archives, the filter archive entries must pass (by
+    /// extension only).
+    entry_filter: Option<FileFilter>,
+}
+
+impl FileOpener {
+    fn new(args: &Args, re: &Matcher) -> Self {
+        FileOpener {
+            scanner: build_scanner(re, args),
+            prefilter: literal_prefilter(args),
+            required: build_required(args),
+            preprocessor: args.pre.as_ref().map(|command| Preprocessor {
+                command: command.clone(),
+                globs: build_globset(&args.pre_glob, false),
+            }),
+            entry_filter: args.search_archives.then(|| FileFilter::new(args)),
+        }
+    }
+
+    /// Returns the format of `path` when it is searched entry by entry.
+    fn archive(&self, path: &Path) -> Option<ArchiveKind> {
+        self.entry_filter.as_ref().and(archive_kind(path))
+    }
+
+    /// Calls `search` with the name and contents of each file entry in the
+    /// archive at `path` whose extension is searched.
+    fn for_each_entry(
+        &self,
+        path: &Path,
+        kind: ArchiveKind,
+        args: &Args,
+        mut search: impl FnMut(&str, Box<dyn BufRead>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let Some(filter) = &self.entry_filter else {
+            return Ok(());
+        };
+        let file = File::open(path)?;
+        let mut contents = Vec::new();
+        if let ArchiveKind::Zip = kind {
+            let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                let name = entry.name()?.into_owned();
+                if !entry.is_file() || !filter.allows_extension(Path::new(&name)) {
+                    continue;
+                }
+                contents.clear();
+                entry.read_to_end(&mut contents)?;
+                let label = format!("{}!{}", path.display(), name);
+                search(&name, self.entry_reader(&label, &contents, args)?)?;
+            }
+            return Ok(());
+        }
+        let reader: Box<dyn Read> = match kind {
+            ArchiveKind::TarGz => Box::new(MultiGzDecoder::new(file)),
+            _ => Box::new(file),
+        };
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if !entry.header().entry_type().is_file() || !filter.allows_extension(Path::new(&name))
+            {
+                continue;
+            }
+            contents.clear();
+            entry.read_to_end(&mut contents)?;
+            let label = format!("{}!{}", path.display(), name);
+            search(&name, self.entry_reader(&label, &contents, args)?)?;
+        }
+        Ok(())
+    }
+
+    /// Opens an archive entry's contents like `open` opens a file.
+    fn entry_reader(
+        &self,
+        label: &str,
+        contents: &[u8],
+        args: &Args,
+    ) -> io::Result<Box<dyn BufRead>> {
+        let reader = |label| decode_input(Cursor::new(contents.to_vec()), label, args);
+        if !self.required.is_empty() && !self.satisfies_all(reader(None), args)? {
+            return Ok(Box::new(io::empty()));
+        }
+        Ok(reader(Some(label)))
+    }
+
+    /// Memory-maps `path` for a whole-buffer scan when the output allows one
+    /// and the file would be mapped anyway; `None` means it is opened
+    /// normally, which also reports any error opening it.
+    fn map_for_scan(&self, path: &Path, args: &Args) -> Option<Mmap> {
+        self.scanner.as_ref()?;
+        if self.preprocesses(path) || (args.search_zip && is_gzip(path)) {
+            return None;
+        }
+        map_file(&File::open(path).ok()?, args)
+    }
+
+    /// Returns the text of a file mapped by `map_for_scan` for
+    /// [`scan_mapped`], unless it is binary or not valid UTF-8, which only
+    /// the line reader handles. A file without the prefilter's literal is
+    /// scanned as empty, without being validated.
+    fn mapped_source<'m>(
+        &'m self,
+        map: &'m Mmap,
+        args: &Args,
+    ) -> Option<LineSource<'m, io::Empty>> {
+        let scanner = self.scanner.as_ref()?;
+        if lacks_literal(self.prefilter.as_ref(), map) {
+            return Some(LineSource::Mapped {
+                text: "",
+                offset: 0,
+                scanner,
+            });
+        }
+        if !args.text && looks_binary(map) {
+            return None;
+        }
+        let offset = if map.starts_with(UTF8_BOM) {
+            UTF8_BOM.len()
+        } else {
+            0
+        };
+        Some(LineSource::Mapped {
+            text: std::str::from_utf8(&map[offset..]).ok()?,
+            offset,
+            scanner,
+        })
+    }
+
+    /// Returns true if `path` is searched through the --pre command.
+    fn preprocesses(&self, path: &Path) -> bool {
+        self.preprocessor
+            .as_ref()
+            .is_some_and(|pre| pre.applies(path))
+    }
+
+    fn open(&self, path: &Path, args: &Args) -> io::Result<Box<dyn BufRead>> {
+        if !self.required.is_empty()
+            && !self.satisfies_all(self.open_contents(path, false, args)?, args)?
+        {
+            return Ok(Box::new(io::empty()));
+        }
+        self.open_contents(path, true, args)
+    }
+
+    /// Applies --and to an input that can only be read once, such as stdin,
+    /// by buffering it: returns its contents if every requirement matches,
+    /// otherwise an empty input.
+    fn require_all(
+        &self,
+        mut reader: Box<dyn BufRead>,
+        args: &Args,
+    ) -> io::Result<Box<dyn BufRead>> {
+        if self.required.is_empty() {
+            return Ok(reader);
+        }
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        if !self.satisfies_all(contents.as_slice(), args)? {
+            return Ok(Box::new(io::empty()));
+        }
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    /// Returns true if every --and requirement matches somewhere in `reader`.
+    fn satisfies_all<R: BufRead>(&self, reader: R, args: &Args) -> io::Result<bool> {
+        if args.multiline {
+            let contents = read_all(reader, args)?;
+            return Ok(self.required.iter().all(|re| re.is_match(&contents)));
+        }
+        let mut pending: Vec<&Matcher> = self.required.iter().collect();
+        let mut lines = LineReader::new(reader, args);
+        while !pending.is_empty() {
+            let Some(line) = lines.next_line()? else {
+                break;
+            };
+            pending.retain(|re| !re.is_match(&line.text));
+        }
+        Ok(pending.is_empty())
+    }
+
+    /// Opens `path` for reading; `report` says whether a detected encoding
+    /// is reported, so a file opened twice is reported once.
+    fn open_contents(
+        &self,
+        path: &Path,
+        report: bool,
+        args: &Args,
+    ) -> io::Result<Box<dyn BufRead>> {
+        let shown = path.display().to_string();
+        let label = report.then_some(shown.as_str());
+        let prefilter = self.prefilter.as_ref();
+        if let Some(pre) = self.preprocessor.as_ref().filter(|pre| pre.applies(path)) {
+            let output = pre.run(path)?;
+            if lacks_literal(prefilter, &output) {
+                return Ok(Box::new(io::empty()));
+            }
+            return Ok(decode_input(Cursor::new(output), label, args));
+        }
+        let file = File::open(path)?;
+        if args.search_zip && is_gzip(path) {
+            return Ok(decode_input(
+                BufReader::new(MultiGzDecoder::new(file)),
+                label,
+                args,
+            ));
+        }
+        if let Some(map) = map_file(&file, args) {
+            if lacks_literal(prefilter, &map) {
+                return Ok(Box::new(io::empty()));
+            }
+            return Ok(decode_input(Cursor::new(map), label, args));
+        }
+        if prefilter.is_some() && file.metadata()?.len() < MMAP_THRESHOLD {
+            let mut bytes = Vec::new();
+            (&file).read_to_end(&mut bytes)?;
+            if lacks_literal(prefilter, &bytes) {
+                return Ok(Box::new(io::empty()));
+            }
+            return Ok(decode_input(Cursor::new(bytes), label, args));
+        }
+        Ok(decode_input(BufReader::new(file), label, args))
+    }
+}
+
+/// Buffered outcome of searching one file, printed once all files are done.
+struct FileResult {
+    counts: Counts,
+    output: Vec<u8>,
+    /// Set when the file was searched but rewriting it failed.
+    errored: bool,
+}
+
+/// Searches every entry of an archive as if it were a file named
+/// `archive!entry`, returning the combined counts. Archives are never
+/// rewritten.
+fn search_archive<W: Write>(
+    path: &Path,
+    kind: ArchiveKind,
+    re: &Matcher,
+    opener: &FileOpener,
+    args: &Args,
+    style: &OutputStyle,
+    out: &mut W,
+) -> io::Result<Counts> {
+    let archive = style.label(path);
+    let (before, after) = context_lines(args);
+    let mut total = Counts::default();
+    let mut printed_any = false;
+    opener.for_each_entry(path, kind, args, |name, reader| {
+        let label = format!("{}!{}", archive, name);
+        let mut output = Vec::new();
+        let counts = search_reader(reader, &label, re, args, style, &mut output)?;
+        total.lines += counts.lines;
+        total.matches += counts.matches;
+        if !output.is_empty() {
+            if printed_any {
+                style.write_file_break(out, before > 0 || after > 0)?;
+            }
+            out.write_all(&output)?;
+            printed_any = true;
+        }
+        Ok(())
+    })?;
+    Ok(total)
+}
+
+/// Searches one file into an in-memory buffer so it can run on any thread.
+fn search_file(
+    path: &Path,
+    re: &Matcher,
+    opener: &FileOpener,
+    args: &Args,
+    style: &OutputStyle,
+) -> io::Result<FileResult> {
+    if let Some(kind) = opener.archive(path) {
+        let mut output = Vec::new();
+        let counts = search_archive(path, kind, re, opener, args, style, &mut output)?;
+        return Ok(FileResult {
+            counts,
+            output,
+            errored: false,
+        });
+    }
+    let label = style.label(path);
+    let mut output = Vec::new();
+    let map = opener.map_for_scan(path, args);
+    let counts = match map.as_ref().and_then(|map| opener.mapped_source(map, args)) {
+        Some(source) => print_matches(source, &label, re, args, style, &mut output)?,
+        None => search_reader(
+            opener.open(path, args)?,
+            &label,
+            re,
+            args,
+            style,
+            &mut output,
+        )?,
+    };
+    let matched = counts.lines > 0;
+    let mut errored = false;
+    if let (true, Some(replacement), Some(suffix)) = (matched, &args.replace, &args.in_place) {
+        // Compressed and preprocessed files are searched but never rewritten.
+        let compressed = args.search_zip && is_gzip(path);
+        let listing = args.list_files || args.files_without_match;
+        if !args.count && !listing && !compressed && !opener.preprocesses(path) {
+            if let Err(e) = rewrite_file(path, re, args, replacement, suffix) {
+                if !args.no_messages {
+                    eprintln!("Error rewriting {}: {}", label, e);
+                }
+                errored = true;
+            }
+        }
+    }
+    Ok(FileResult {
+        counts,
+        output,
+        errored,
+    })
+}
+
+/// A glob given as the prefix, split into the literal directory the walk
+/// starts from and the pattern matched below it.
+struct PrefixGlob {
+    base: PathBuf,
+    matcher: GlobMatcher,
+}
+
+impl PrefixGlob {
+    /// Returns the glob in `prefix`, or `None` if it has no glob
+    /// metacharacters or names an existing path; exits with code 2 on an
+    /// invalid glob.
+    fn parse(prefix: &str) -> Option<Self> {
+        let is_glob = |part: &str| part.contains(['*', '?', '[', '{']);
+        if !is_glob(prefix) || Path::new(prefix).exists() {
+            return None;
+        }
+        let mut base = PathBuf::new();
+        let mut components = Path::new(prefix).components().peekable();
+        while let Some(part) = components.next_if(|c| !is_glob(&c.as_os_str().to_string_lossy())) {
+            base.push(part);
+        }
+        let pattern: PathBuf = components.collect();
+        let glob = GlobBuilder::new(&pattern.to_string_lossy())
+            .literal_separator(true)
+            .build()
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid glob '{}': {}", prefix, e);
+                std::process::exit(2);
+            });
+        if base.as_os_str().is_empty() {
+            base.push(".");
+        }
+        Some(PrefixGlob {
+            base,
+            matcher: glob.compile_matcher(),
+        })
+    }
+
+    /// Returns true if a walked `path` matches the glob below its base.
+    fn matches(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.base)
+            .is_ok_and(|relative| self.matcher.is_match(relative))
+    }
+}
+
+/// Returns true if a walk error reports a symlink cycle (with --follow).
+fn is_loop(err: &ignore::Error) -> bool {
+    match err {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_loop(err),
+        ignore::Error::Partial(errs) => errs.iter().any(is_loop),
+        _ => false,
+    }
+}
+
+/// Walks `root` and returns every file that passes the ignore, hidden, and
+/// depth rules as well as `filter`, setting `errored` if any entry could not
+/// be read.
+fn walk_files(root: &str, args: &Args, filter: &FileFilter, errored: &mut bool) -> Vec<PathBuf> {
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .standard_filters(!args.no_ignore)
+        .hidden(!args.hidden)
+        .follow_links(args.follow)
+        .max_filesize(args.max_filesize)
+        .max_depth(Some(args.max_depth.saturating_add(1)));
+    let exclude_dirs = filter.exclude_dirs.clone();
+    // The root itself may be a link; only links below it must stay inside.
+    let root_dir = args
+        .no_escape_root
+        .then(|| fs::canonicalize(root).ok())
+        .flatten();
+    let report_escapes = args.stats;
+    if exclude_dirs.is_some() || root_dir.is_some() {
+        // Pruning here keeps the walk from ever entering excluded trees.
+        builder.filter_entry(move |e| {
+            if e.depth() == 0 {
+                return true;
+            }
+            if let Some(root_dir) = &root_dir {
+                if e.path_is_symlink() {
+                    let target = fs::canonicalize(e.path());
+                    if !target.as_ref().is_ok_and(|t| t.starts_with(root_dir)) {
+                        if report_escapes {
+                            eprintln!(
+                                "Skipped {}: link leaves {}",
+                                e.path().display(),
+                                root_dir.display()
+                            );
+                        }
+                        return false;
+                    }
+                }
+            }
+            let is_dir = e.file_type().is_some_and(|t| t.is_dir());
+            !is_dir
+                || exclude_dirs
+                    .as_ref()
+                    .is_none_or(|exclude_dirs| !glob_matches(exclude_dirs, e.path()))
+        });
+    }
+    builder
+        .build()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) if is_loop(&e) => {
+                if !args.no_messages {
+                    eprintln!("Warning: {}", e);
+                }
+                None
+            }
+            Err(e) => {
+                if !args.no_messages {
+                    eprintln!("Error reading {}", e);
+                }
+                *errored = true;
+                None
+            }
+        })
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .filter(|e| filter.matches(e.path()))
+        .filter(|e| filter.modified_in_window(e))
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// Collects the files to search under every prefix: a file named explicitly
+/// is searched as-is, bypassing all filters; globs are expanded and
+/// directories walked. A file reached through several prefixes (such as
+/// `src` and `src/lib`) is searched only once.
+fn collect_paths(
+    prefixes: &[String],
+    args: &Args,
+    filter: &FileFilter,
+    errored: &mut bool,
+) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for prefix in prefixes {
+        if Path::new(prefix).is_file() {
+            paths.push(PathBuf::from(prefix));
+        } else if let Some(glob) = PrefixGlob::parse(prefix) {
+            // The glob already says which files are wanted, so the default
+            // extension list does not apply; --include/--exclude still do.
+            let filter = FileFilter {
+                extensions: None,
+                ..filter.clone()
+            };
+            let base = glob.base.to_string_lossy();
+            let mut matched = walk_files(&base, args, &filter, errored);
+            matched.retain(|path| glob.matches(path));
+            paths.extend(matched);
+        } else {
+            paths.extend(walk_files(prefix, args, filter, errored));
+        }
+    }
+    if prefixes.len() > 1 {
+        let mut seen = HashSet::new();
+        paths.retain(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone())));
+    }
+    paths
+}
+
+/// Searches files one at a time until `limit` matching lines have been
+/// found, capping each file's -m at what is left.
+fn search_capped(
+    paths: &[PathBuf],
+    limit: usize,
+    re: &Matcher,
+    opener: &FileOpener,
+    args: &Args,
+    style: &OutputStyle,
+    progress: &Progress,
+) -> Vec<io::Result<FileResult>> {
+    let mut capped = args.clone();
+    let mut remaining = limit;
+    let mut results = Vec::new();
+    for path in paths {
+        if remaining == 0 {
+            break;
+        }
+        capped.max_count = Some(args.max_count.map_or(remaining, |max| max.min(remaining)));
+        let result = search_file(path, re, opener, &capped, style);
+        progress.tick();
+        if let Ok(result) = &result {
+            remaining = remaining.saturating_sub(result.counts.lines);
+        }
+        results.push(result);
+    }
+    results
+}
+
+/// Returns the directory whose total --count-by-dir adds `path` to: the
+/// immediate subdirectory of the prefix it was found under, or the directory
+/// holding it for files directly inside a prefix or named explicitly.
+fn count_dir(path: &Path, prefixes: &[String]) -> PathBuf {
+    for prefix in prefixes {
+        let Ok(rest) = path.strip_prefix(prefix) else {
+            continue;
+        };
+        let mut components = rest.components();
+        if let (Some(first), Some(_)) = (components.next(), components.next()) {
+            return Path::new(prefix).join(first);
+        }
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Prints each directory's total as `dir/:count`, sorted by directory, then
+/// `total:count` over every file. Directories without a match are left out
+/// unless --include-zero is given.
+fn print_dir_counts<W: Write>(
+    totals: BTreeMap<PathBuf, usize>,
+    style: &OutputStyle,
+    out: &mut W,
+    args: &Args,
+) -> io::Result<()> {
+    let sep = if style.null {
+        "\0"
+    } else {
+        &style.field_separator
+    };
+    let mut total = 0;
+    for (dir, count) in totals {
+        total += count;
+        if count == 0 && !args.include_zero {
+            continue;
+        }
+        let label = format!("{}{}", style.label(&dir), MAIN_SEPARATOR);
+        write!(
+            out,
+            "{}{}{}{}",
+            style.paint_path(&label, None),
+            sep,
+            paint(&count.to_string(), style.match_color),
+            style.eol
+        )?;
+    }
+    let total = paint(&total.to_string(), style.match_color);
+    write!(out, "total{}{}{}", sep, total, style.eol)
+}
+
+/// Orders `paths` by --sort/--sortr, by path when neither is given. Files
+/// whose timestamp cannot be read sort first; ties keep path order.
+fn sort_paths(paths: &mut [PathBuf], args: &Args) {
+    paths.sort();
+    let key = args.sort.or(args.sortr).unwrap_or(SortKey::Path);
+    match key {
+        SortKey::Path => {}
+        SortKey::Modified => {
+            paths.sort_by_cached_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        }
+        SortKey::Created => {
+            paths.sort_by_cached_key(|path| fs::metadata(path).and_then(|m| m.created()).ok())
+        }
+    }
+    if args.sortr.is_some() {
+        paths.reverse();
+    }
+}
+
+/// Running count of searched files for --progress, redrawn in place on
+/// stderr at most every `PROGRESS_INTERVAL`.
+struct Progress {
+    enabled: bool,
+    total: usize,
+    searched: AtomicUsize,
+    last_draw: Mutex<Instant>,
+}
+
+impl Progress {
+    fn new(enabled: bool, total: usize) -> Self {
+        Progress {
+            enabled,
+            total,
+            searched: AtomicUsize::new(0),
+            last_draw: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Records one more searched file, redrawing the count if it is due.
+    fn tick(&self) {
+        if !self.enabled {
+            return;
+        }
+        let searched = self.searched.fetch_add(1, Ordering::Relaxed) + 1;
+        // A thread that finds another one drawing just skips this update.
+        if let Ok(mut last_draw) = self.last_draw.try_lock() {
+            if last_draw.elapsed() >= PROGRESS_INTERVAL {
+                *last_draw = Instant::now();
+                eprint!("\rSearched {}/{} files", searched, self.total);
+            }
+        }
+    }
+
+    /// Clears the progress line so later output starts on a clean line.
+    fn finish(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// Returns `$XDG_CACHE_HOME/ggrep`, falling back to `~/.cache/ggrep`.
+fn cache_dir() -> Option<PathBuf> {
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("ggrep"))
+}
+
+/// Per-file search results saved by --cache, one JSON file per search
+/// fingerprint. An entry is reused while the file's size and modification
+/// time are unchanged.
+struct ResultCache {
+    path: PathBuf,
+    entries: Mutex<serde_json::Map<String, serde_json::Value>>,
+    changed: AtomicBool,
+}
+
+impl ResultCache {
+    /// Loads the cache for this search, or returns `None` when --cache is off
+    /// or results depend on more than one file at a time (--unique,
+    /// --max-matches, and --in-place).
+    fn open(args: &Args, style: &OutputStyle) -> Option<ResultCache> {
+        if !args.cache || args.unique || args.max_matches.is_some() || args.in_place.is_some() {
+            return None;
+        }
+        let path = cache_dir()?.join(format!("{:016x}.json", Self::key(args, style)));
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        Some(ResultCache {
+            path,
+            entries: Mutex::new(entries),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    /// Hashes everything that shapes a file's output, except which files are
+    /// searched: the flags and the whole resolved output style, which also
+    /// depends on the terminal.
+    fn key(args: &Args, style: &OutputStyle) -> u64 {
+        let mut key = args.clone();
+        key.prefixes.clear();
+        key.progress = false;
+        key.stats = false;
+        let fingerprint = format!(
+            "{} {:?} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            key,
+            collect_patterns(args),
+            env::current_dir().ok(),
+            style
+        );
+        let mut hasher = DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the size and modification time recorded for `path`.
+    fn stamp(path: &Path) -> Option<serde_json::Value> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?;
+        Some(json!([
+            metadata.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        ]))
+    }
+
+    /// Returns the cached result for `path`, or runs `search` and records its
+    /// result. Failed searches are never cached.
+    fn search(
+        &self,
+        path: &Path,
+        search: impl FnOnce() -> io::Result<FileResult>,
+    ) -> io::Result<FileResult> {
+        let key = path.to_string_lossy().into_owned();
+        let stamp = Self::stamp(path);
+        if let Some(stamp) = &stamp {
+            let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(entry) = entries.get(&key).filter(|entry| entry["stamp"] == *stamp) {
+                if let (Some(lines), Some(matches), Some(output)) = (
+                    entry["lines"].as_u64(),
+                    entry["matches"].as_u64(),
+                    entry["output"].as_str(),
+                ) {
+                    return Ok(FileResult {
+                        counts: Counts {
+                            lines: lines as usize,
+                            matches: matches as usize,
+                        },
+                        output: output.as_bytes().to_vec(),
+                        errored: false,
+                    });
+                }
+            }
+        }
+        let result = search()?;
+        if let (Some(stamp), Ok(output)) = (stamp, std::str::from_utf8(&result.output)) {
+            let entry = json!({
+                "stamp": stamp,
+                "lines": result.counts.lines,
+                "matches": result.counts.matches,
+                "output": output,
+            });
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            entries.insert(key, entry);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        Ok(result)
+    }
+
+    /// Writes the cache back if anything was added, replacing the file
+    /// atomically so concurrent runs never read a partial cache.
+    fn save(self) {
+        if !self.changed.into_inner() {
+            return;
+        }
+        let entries = self.entries.into_inner().unwrap_or_else(|e| e.into_inner());
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let saved = fs::create_dir_all(dir)
+            .and_then(|()| tempfile::NamedTempFile::new_in(dir))
+            .and_then(|mut file| {
+                file.write_all(serde_json::Value::Object(entries).to_string().as_bytes())?;
+                file.persist(&self.path).map_err(|e| e.error)?;
+                Ok(())
+            });
+        if let Err(e) = saved {
+            eprintln!("Warning: cannot write cache {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Totals across all inputs, printed by --stats.
+#[derive(Default)]
+struct Stats {
+    searched: usize,
+    matched: usize,
+    lines: usize,
+    matches: usize,
+}
+
+impl Stats {
+    fn add(&mut self, counts: Counts) {
+        self.searched += 1;
+        self.matched += usize::from(counts.lines > 0);
+        self.lines += counts.lines;
+        self.matches += counts.matches;
+    }
+
+    fn print(&self, elapsed: Duration, args: &Args) {
+        eprintln!();
+        eprintln!("{} files searched", self.searched);
+        eprintln!("{} files with matches", self.matched);
+        eprintln!("{} matching lines", self.lines);
+        eprintln!("{} matches", self.matches);
+        if args.replace.is_some() && !args.invert_match {
+            eprintln!("{} substitutions", self.matches);
+        }
+        eprintln!("{:.3}s elapsed", elapsed.as_secs_f64());
+    }
+
+    /// Writes the closing --json record with the totals.
+    fn write_json<W: Write>(&self, elapsed: Duration, out: &mut W) -> io::Result<()> {
+        let summary = json!({
+            "type": "summary",
+            "files_searched": self.searched,
+            "files_matched": self.matched,
+            "lines": self.lines,
+            "matches": self.matches,
+            "elapsed_ms": elapsed.as_millis() as u64,
+        });
+        writeln!(out, "{}", summary)
+    }
+}
+
+/// Returns `$XDG_CONFIG_HOME/ggrep/config.toml`, falling back to
+/// `~/.config/ggrep/config.toml`.
+fn config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("ggrep").join("config.toml"))
+}
+
+/// Translates the config file into `--key=value` arguments. Keys are long
+/// flag names (`max-depth` or `max_depth`); `true` enables a flag, `false`
+/// is ignored, and arrays repeat the flag. A missing file yields nothing.
+fn config_args() -> Vec<OsString> {
+    let Some(path) = config_path() else {
+        return Vec::new();
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            eprintln!("Error reading {}: {}", path.display(), e);
+            std::process::exit(2);
+        }
+    };
+    let table: toml::Table = contents.parse().unwrap_or_else(|e| {
+        eprintln!("Invalid config file {}: {}", path.display(), e);
+        std::process::exit(2);
+    });
+    let mut config = Vec::new();
+    for (key, value) in &table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            toml::Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => config.push(flag.clone()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(text) => config.push(format!("{}={}", flag, text)),
+                toml::Value::Integer(_) | toml::Value::Float(_) => {
+                    config.push(format!("{}={}", flag, value))
+                }
+                _ => {
+                    eprintln!("Invalid value for '{}' in {}", key, path.display());
+                    std::process::exit(2);
+                }
+            }
+        }
+    }
+    config.into_iter().map(OsString::from).collect()
+}
+
+/// Splits `GGREP_OPTS` into arguments, honoring shell-style quoting.
+fn env_args() -> Vec<OsString> {
+    let Some(opts) = env::var_os("GGREP_OPTS") else {
+        return Vec::new();
+    };
+    let split = opts.to_str().and_then(shlex::split).unwrap_or_else(|| {
+        eprintln!("Invalid GGREP_OPTS: {}", opts.to_string_lossy());
+        std::process::exit(2);
+    });
+    split.into_iter().map(OsString::from).collect()
+}
+
+/// Builds the argument vector: config defaults, then `GGREP_OPTS`, then the
+/// command line. An option given in a more explicit layer replaces the
+/// defaults for it and drops defaults it conflicts with, so `--ext py`
+/// replaces a configured extension list and `--all-files` overrides it.
+fn command_line() -> Vec<OsString> {
+    let argv: Vec<OsString> = env::args_os().collect();
+    let env = env_args();
+    let cli = argv.iter().skip(1).take_while(|arg| *arg != "--");
+    let config = if cli.chain(&env).any(|arg| arg == "--no-config") {
+        Vec::new()
+    } else {
+        config_args()
+    };
+    let mut cmd = Args::command();
+    cmd.build();
+    let env = without_overridden(&cmd, env, &argv);
+    let mut above = argv.clone();
+    above.splice(1..1, env.iter().cloned());
+    let config = without_overridden(&cmd, config, &above);
+    above.splice(1..1, config);
+    above
+}
+
+/// Drops the options in `defaults` that `argv` (starting with the program
+/// name) sets itself or conflicts with.
+fn without_overridden(
+    cmd: &clap::Command,
+    defaults: Vec<OsString>,
+    argv: &[OsString],
+) -> Vec<OsString> {
+    if defaults.is_empty() {
+        return defaults;
+    }
+    let given = given_args(cmd, argv);
+    let overridden = |arg: &clap::Arg| {
+        given.iter().any(|other| {
+            other.get_id() == arg.get_id()
+                || cmd.get_arg_conflicts_with(arg).contains(other)
+                || cmd.get_arg_conflicts_with(other).contains(&arg)
+        })
+    };
+    option_groups(cmd, defaults)
+        .into_iter()
+        .filter(|(arg, _)| !arg.is_some_and(overridden))
+        .flat_map(|(_, tokens)| tokens)
+        .collect()
+}
+
+/// Returns the options that `argv` sets on the command line, as clap parses
+/// it; parse errors are left for the real parse to report.
+fn given_args<'c>(cmd: &'c clap::Command, argv: &[OsString]) -> Vec<&'c clap::Arg> {
+    let Ok(matches) = cmd.clone().ignore_errors(true).try_get_matches_from(argv) else {
+        return Vec::new();
+    };
+    cmd.get_arguments()
+        .filter(|arg| {
+            matches.value_source(arg.get_id().as_str())
+                == Some(clap::parser::ValueSource::CommandLine)
+        })
+        .collect()
+}
+
+/// Splits default arguments into one group per option (the flag and any
+/// separate value), paired with the option it sets; clustered short flags
+/// such as `-iC2` are split into `-i` and `-C2`. Positionals and unknown
+/// flags pair with `None` and are always kept.
+fn option_groups(
+    cmd: &clap::Command,
+    tokens: Vec<OsString>,
+) -> Vec<(Option<&clap::Arg>, Vec<OsString>)> {
+    let takes_value = |arg: &clap::Arg| arg.get_action().takes_values();
+    let mut groups = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        let text = token.to_string_lossy().into_owned();
+        if let Some(long) = text.strip_prefix("--").filter(|long| !long.is_empty()) {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, _)) => (name, true),
+                None => (long, false),
+            };
+            let arg = cmd.get_arguments().find(|arg| {
+                arg.get_long_and_visible_aliases()
+                    .is_some_and(|longs| longs.contains(&name))
+            });
+            let mut group = vec![token];
+            if arg.is_some_and(|arg| takes_value(arg) && !arg.is_require_equals_set()) && !inline {
+                group.extend(tokens.next());
+            }
+            groups.push((arg, group));
+        } else if let Some(shorts) = text.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            for (i, short) in shorts.char_indices() {
+                let arg = cmd.get_arguments().find(|arg| {
+                    arg.get_short_and_visible_aliases()
+                        .is_some_and(|chars| chars.contains(&short))
+                });
+                let rest = &shorts[i + short.len_utf8()..];
+                if !arg.is_some_and(takes_value) {
+                    groups.push((arg, vec![OsString::from(format!("-{}", short))]));
+                    continue;
+                }
+                // The first flag taking a value takes the rest of the cluster.
+                let mut group = vec![OsString::from(format!("-{}{}", short, rest))];
+                if rest.is_empty() {
+                    group.extend(tokens.next());
+                }
+                groups.push((arg, group));
+                break;
+            }
+        } else {
+            groups.push((None, vec![token]));
+        }
+    }
+    groups
+}
+
+/// Reports a failure to search `label` unless -s is given. Returns whether
+/// it counts towards exit code 2: invalid UTF-8 under --encoding-errors=skip
+/// only drops the file with a warning.
+fn report_read_error(err: &io::Error, label: &str, args: &Args) -> bool {
+    let skipped = args.encoding_errors == EncodingErrors::Skip && is_invalid_utf8(err);
+    if !args.no_messages && skipped {
+        eprintln!("Warning: skipping {}: {}", label, err);
+    } else if !args.no_messages {
+        eprintln!("Error reading {}: {}", label, err);
+    }
+    !skipped
+}
+
+/// Maps the search outcome to grep's exit codes: 0 if anything matched,
+/// 1 if nothing did, and 2 if any file could not be read. As with grep, a
+/// match found under -q wins over errors.
+fn exit_code(matched: bool, errored: bool, args: &Args) -> ExitCode {
+    if errored && !(args.quiet && matched) {
+        ExitCode::from(2)
+    } else if matched {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Runs the ggrep command line: walks directory, applies search logic, and
+/// sets exit code.
+pub fn run() -> ExitCode {
+    let started = Instant::now();
+    let mut args = Args::parse_from(command_line());
+    if let Some(shell) = args.generate_completions {
+        // Generating into a buffer keeps a closed pipe from panicking.
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Args::command(), "ggrep", &mut script);
+        let _ = io::stdout().write_all(&script);
+        return ExitCode::SUCCESS;
+    }
+    if args.generate_man {
+        let mut page = Vec::new();
+        if let Err(e) = clap_mangen::Man::new(Args::command()).render(&mut page) {
+            eprintln!("Cannot render man page: {}", e);
+            return ExitCode::from(2);
+        }
+        let _ = io::stdout().write_all(&page);
+        return ExitCode::SUCCESS;
+    }
+    // With -e, -f, or --literal-file every positional names a prefix, as in
+    // grep; --files takes no pattern at all.
+    let explicit_patterns = !args.regexp.is_empty()
+        || !args.pattern_files.is_empty()
+        || !args.literal_files.is_empty()
+        || args.files;
+    if explicit_patterns {
+        if let Some(prefix) = args.keyword.take() {
+            args.prefixes.insert(0, prefix);
+        }
+    }
+    args.count |= args.count_matches || args.count_by_dir;
+    if args.sort_files {
+        args.sort = Some(SortKey::Path);
+    }
+    if args.ignore_case_fixed {
+        args.fixed_strings = true;
+        args.ignore_case = true;
+    }
+    if args.no_recursive {
+        args.max_depth = 0;
+    }
+    if args.vimgrep {
+        // Quickfix lines always carry the path and are never colored or grouped.
+        args.with_filename = true;
+        args.no_filename = false;
+        args.heading = false;
+        args.color = ColorMode::Never;
+        args.line_number = true;
+        args.no_line_number = false;
+    }
+    let re = build_matcher(&args);
+    let opener = FileOpener::new(&args, &re);
+    let filter = FileFilter::new(&args);
+
+    if let Some(threads) = args.threads.filter(|&threads| threads > 0) {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("global thread pool is only configured once");
+    }
+
+    // Determine whether to colorize output; JSON and NUL-terminated records
+    // are never colorized, and NO_COLOR (https://no-color.org) turns auto
+    // mode off
+    let colorize = !args.json
+        && !args.print0_matches
+        && match args.color {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => env::var_os("NO_COLOR").is_none() && atty::is(Stream::Stdout),
+        };
+
+    let separator_color = colorize.then_some(Colour::Cyan);
+
+    let read_stdin = match args.prefixes.as_slice() {
+        [] => !args.files && !args.name_match && !atty::is(Stream::Stdin),
+        [only] => only == "-",
+        several if several.iter().any(|p| p == "-") => {
+            eprintln!(
+                "{} cannot be searched along with other prefixes",
+                STDIN_LABEL
+            );
+            return ExitCode::from(2);
+        }
+        _ => false,
+    };
+    if args.prefixes.is_empty() {
+        args.prefixes.push(".".to_string());
+    }
+    // File names are shown by default unless a single named file is searched.
+    // An archive holds several files, so its entry names are always shown.
+    let single_file = matches!(args.prefixes.as_slice(), [only]
+        if Path::new(only).is_file() && opener.archive(Path::new(only)).is_none());
+    let filename = if args.no_filename {
+        false
+    } else {
+        args.with_filename || read_stdin || !single_file
+    };
+    let style = OutputStyle {
+        match_color: colorize.then(|| args.color_match.ansi()),
+        line_color: args.color_line.filter(|_| colorize).map(ColorName::ansi),
+        path_color: colorize.then(|| args.color_path.ansi()),
+        filename,
+        line_number: if args.line_number || args.no_line_number {
+            args.line_number
+        } else {
+            atty::is(Stream::Stdout)
+        },
+        heading: args.heading
+            && filename
+            && !args.json
+            && !args.count
+            && !args.list_files
+            && !args.files_without_match,
+        null: args.null,
+        path_separator: args.path_separator,
+        path_form: match env::current_dir() {
+            _ if args.absolute_path => PathForm::Absolute,
+            Ok(cwd) if args.relative_path => PathForm::Relative(cwd),
+            _ => PathForm::AsGiven,
+        },
+        eol: if args.null_data || args.print0_matches {
+            '\0'
+        } else {
+            '\n'
+        },
+        group_separator: paint(&args.group_separator, separator_color),
+        context_separator: paint(&args.context_separator, separator_color),
+        // Quickfix parsers expect colons.
+        field_separator: if args.vimgrep {
+            ":".to_string()
+        } else {
+            args.field_separator.clone()
+        },
+        seen: args.unique.then(|| Mutex::new(HashSet::new())),
+    };
+
+    let mut any_match = false;
+    let mut errored = false;
+    let mut stats = Stats::default();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if read_stdin {
+        if args.in_place.is_some() {
+            eprintln!("--in-place cannot be used with {}", STDIN_LABEL);
+            return ExitCode::from(2);
+        }
+        let stdin = decode_input(io::stdin().lock(), Some(STDIN_LABEL), &args);
+        let stdin = match opener.require_all(stdin, &args) {
+            Ok(stdin) => stdin,
+            Err(e) => {
+                errored |= report_read_error(&e, STDIN_LABEL, &args);
+                Box::new(io::empty())
+            }
+        };
+        if args.tally {
+            let mut tally = HashMap::new();
+            if let Err(e) = tally_matches(stdin, &re, &args, &mut tally) {
+                errored |= report_read_error(&e, STDIN_LABEL, &args);
+            }
+            let found = !tally.is_empty();
+            print_tally(tally, &style, &mut out);
+            return exit_code(found, errored, &args);
+        }
+        let result = if args.quiet {
+            has_match(stdin, Path::new(STDIN_LABEL), &re, &args)
+                .map(|found| found != args.files_without_match)
+        } else {
+            search_reader(stdin, STDIN_LABEL, &re, &args, &style, &mut out).map(|counts| {
+                stats.add(counts);
+                counts.success(&args)
+            })
+        };
+        match result {
+            Ok(found) => any_match = found,
+            // A closed output pipe (as under `| head`) just ends the search,
+            // as it does for files; output is only written once something
+            // matched.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => any_match = true,
+            Err(e) => errored |= report_read_error(&e, STDIN_LABEL, &args),
+        }
+        if args.json && !args.quiet {
+            let _ = stats.write_json(started.elapsed(), &mut out);
+        }
+        if args.stats && !args.quiet {
+            stats.print(started.elapsed(), &args);
+        }
+        return exit_code(any_match, errored, &args);
+    }
+
+    let mut paths = collect_paths(&args.prefixes, &args, &filter, &mut errored);
+    // Sorting up front keeps output deterministic: the windowed search below
+    // prints results in this order.
+    sort_paths(&mut paths, &args);
+    if args.files {
+        for path in &paths {
+            let shown = style.paint_path(&style.label(path), None);
+            if write!(out, "{}{}", shown, style.after_path('\n')).is_err() {
+                break;
+            }
+        }
+        return exit_code(!paths.is_empty(), errored, &args);
+    }
+
+    if args.name_match {
+        // Without a pattern every file is listed, as with --files.
+        if !collect_patterns(&args).is_empty() {
+            // Paths are matched without the walk's leading "./", so "^src/"
+            // works from the current directory.
+            paths.retain(|path| {
+                let name = path.strip_prefix(".").unwrap_or(path).to_string_lossy();
+                args.invert_match ^ re.is_match(&name)
+            });
+        }
+        if !args.quiet {
+            for path in &paths {
+                let shown = style.paint_path(&style.label(path), Some(&re));
+                if write!(out, "{}{}", shown, style.after_path('\n')).is_err() {
+                    break;
+                }
+            }
+        }
+        return exit_code(!paths.is_empty(), errored, &args);
+    }
+
+    let progress = Progress::new(args.progress && atty::is(Stream::Stderr), paths.len());
+    let parallel =
+        args.threads.is_some_and(|threads| threads > 0) || paths.len() >= AUTO_PARALLEL_MIN_FILES;
+
+    if args.quiet {
+        let read_failed = AtomicBool::new(false);
+        let check = |path: &PathBuf| {
+            let result = match opener.archive(path) {
+                Some(kind) => {
+                    search_archive(path, kind, &re, &opener, &args, &style, &mut io::sink())
+                        .map(|counts| counts.success(&args))
+                }
+                // With -L, a file succeeds when nothing in it is selected.
+                None => opener
+                    .open(path, &args)
+                    .and_then(|reader| has_match(reader, path, &re, &args))
+                    .map(|found| found != args.files_without_match),
+            };
+            progress.tick();
+            result.unwrap_or_else(|e| {
+                if report_read_error(&e, &path.display().to_string(), &args) {
+                    read_failed.store(true, Ordering::Relaxed);
+                }
+                false
+            })
+        };
+        let found = if parallel {
+            paths.par_iter().any(check)
+        } else {
+            paths.iter().any(check)
+        };
+        progress.finish();
+        return exit_code(found, errored || read_failed.into_inner(), &args);
+    }
+
+    if args.tally {
+        let read_failed = AtomicBool::new(false);
+        let count_file = |path: &PathBuf| {
+            let mut tally = HashMap::new();
+            let result = opener
+                .open(path, &args)
+                .and_then(|reader| tally_matches(reader, &re, &args, &mut tally));
+            progress.tick();
+            if let Err(e) = result {
+                if report_read_error(&e, &path.display().to_string(), &args) {
+                    read_failed.store(true, Ordering::Relaxed);
+                }
+            }
+            tally
+        };
+        let merge = |mut into: HashMap<String, usize>, from: HashMap<String, usize>| {
+            for (text, count) in from {
+                *into.entry(text).or_default() += count;
+            }
+            into
+        };
+        let tally = if parallel {
+            paths.par_iter().map(count_file).reduce(HashMap::new, merge)
+        } else {
+            paths.iter().map(count_file).fold(HashMap::new(), merge)
+        };
+        progress.finish();
+        let found = !tally.is_empty();
+        print_tally(tally, &style, &mut out);
+        return exit_code(found, errored || read_failed.into_inner(), &args);
+    }
+
+    let cache = ResultCache::open(&args, &style);
+    let search = |path: &PathBuf| {
+        let result = match &cache {
+            Some(cache) => cache.search(path, || search_file(path, &re, &opener, &args, &style)),
+            None => search_file(path, &re, &opener, &args, &style),
+        };
+        progress.tick();
+        result
+    };
+    let (before, after) = context_lines(&args);
+    let with_context = before > 0 || after > 0 || args.context_block;
+    let mut printed_any = false;
+    let mut dir_totals = BTreeMap::new();
+    let mut report = |path: &PathBuf, result: io::Result<FileResult>| {
+        match result {
+            Ok(result) => {
+                any_match |= result.counts.success(&args);
+                errored |= result.errored;
+                stats.add(result.counts);
+                if args.count_by_dir {
+                    *dir_totals
+                        .entry(count_dir(path, &args.prefixes))
+                        .or_default() += result.counts.reported(&args);
+                    return ControlFlow::Continue(());
+                }
+                if result.output.is_empty() {
+                    return ControlFlow::Continue(());
+                }
+                if printed_any && style.write_file_break(&mut out, with_context).is_err() {
+                    return ControlFlow::Break(());
+                }
+                if out.write_all(&result.output).is_err() {
+                    return ControlFlow::Break(());
+                }
+                printed_any = true;
+            }
+            Err(e) => errored |= report_read_error(&e, &path.display().to_string(), &args),
+        }
+        ControlFlow::Continue(())
+    };
+    if let Some(limit) = args.max_matches {
+        let results = search_capped(&paths, limit, &re, &opener, &args, &style, &progress);
+        let _ = paths
+            .iter()
+            .zip(results)
+            .try_for_each(|(path, result)| report(path, result));
+    } else {
+        // Output streams in path order: each window of files is searched
+        // (in parallel unless --unique needs path order to pick the first
+        // copy of a line) and printed before the next one starts, so at most
+        // one window of output is buffered and a closed pipe stops the
+        // search early. Progress redraws would mix with the output, so with
+        // --progress everything is searched first.
+        let window = if progress.enabled {
+            paths.len().max(1)
+        } else if parallel && !args.unique {
+            STREAM_WINDOW
+        } else {
+            1
+        };
+        for chunk in paths.chunks(window) {
+            let results: Vec<io::Result<FileResult>> = if parallel && !args.unique {
+                chunk.par_iter().map(search).collect()
+            } else {
+                chunk.iter().map(search).collect()
+            };
+            let flow = chunk
+                .iter()
+                .zip(results)
+                .try_for_each(|(path, result)| report(path, result));
+            if flow.is_break() {
+                break;
+            }
+        }
+    }
+    progress.finish();
+    if let Some(cache) = cache {
+        cache.save();
+    }
+    if args.count_by_dir {
+        let _ = print_dir_counts(dir_totals, &style, &mut out, &args);
+    }
+
+    if args.json {
+        let _ = stats.write_json(started.elapsed(), &mut out);
+    }
+    if args.stats {
+        stats.print(started.elapsed(), &args);
+    }
+    exit_code(any_match, errored, &args)
+}
+
+/// Options for a [`Searcher`], mirroring the command-line flags of the same
+/// names; the defaults match ggrep's, except that every file extension is
+/// searched.
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// Patterns to search for; a line matching any of them matches.
+    pub patterns: Vec<String>,
+    /// Treat the patterns as literal strings (-F).
+    pub fixed_strings: bool,
+    /// Match case-insensitively (-i).
+    pub ignore_case: bool,
+    /// Match case-insensitively unless a pattern has uppercase letters (-S).
+    pub smart_case: bool,
+    /// Match whole words only (-w).
+    pub word_regexp: bool,
+    /// Match whole lines only (-x).
+    pub line_regexp: bool,
+    /// Select the lines that do not match (-v).
+    pub invert_match: bool,
+    /// Let matches span lines (-U).
+    pub multiline: bool,
+    /// ASCII-only word boundaries, classes, and case folding (--ascii).
+    pub ascii: bool,
+    /// Use fancy-regex for lookaround and backreferences (--engine fancy).
+    pub fancy: bool,
+    /// Stop after this many matching lines per input (-m).
+    pub max_count: Option<usize>,
+    /// Extensions a walked directory is limited to (--ext); empty searches
+    /// every file, unlike the command line's built-in list.
+    pub extensions: Vec<String>,
+    /// Search hidden files and directories (--hidden).
+    pub hidden: bool,
+    /// Don't respect .gitignore, .ignore, or global gitignore rules
+    /// (--no-ignore).
+    pub no_ignore: bool,
+}
+
+/// A matching line found by a [`Searcher`]; with multiline matching, the
+/// whole lines a match spans.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The file the match is in.
+    pub path: PathBuf,
+    /// 1-based number of the (first) line.
+    pub line_number: usize,
+    /// The line without its terminator.
+    pub text: String,
+    /// Byte ranges of the matches within `text`; empty for inverted matches.
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Error returned when a [`Searcher`] cannot be built from its options,
+/// such as when a pattern does not compile.
+#[derive(Debug)]
+pub struct PatternError(String);
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// The search engine behind ggrep, for use as a library: compiles the
+/// patterns once and searches files with them as the command line would,
+/// including its handling of invalid UTF-8.
+pub struct Searcher {
+    args: Args,
+    re: Matcher,
+    opener: FileOpener,
+}
+
+impl Searcher {
+    /// Compiles the patterns in `options`; at least one is required.
+    pub fn new(options: SearchOptions) -> Result<Searcher, PatternError> {
+        if options.patterns.is_empty() {
+            return Err(PatternError("no patterns given".to_string()));
+        }
+        // Start from the command line's defaults, with the patterns given as
+        // -e so no positional is needed, and apply the other options.
+        let argv = std::iter::once("ggrep".to_string()).chain(
+            options
+                .patterns
+                .iter()
+                .map(|pattern| format!("--regexp={}", pattern)),
+        );
+        let mut args = Args::try_parse_from(argv).map_err(|e| PatternError(e.to_string()))?;
+        args.fixed_strings = options.fixed_strings;
+        args.ignore_case = options.ignore_case;
+        args.smart_case = options.smart_case;
+        args.word_regexp = options.word_regexp;
+        args.line_regexp = options.line_regexp;
+        args.invert_match = options.invert_match;
+        args.multiline = options.multiline;
+        args.ascii = options.ascii;
+        args.engine = if options.fancy {
+            Engine::Fancy
+        } else {
+            Engine::Default
+        };
+        args.max_count = options.max_count;
+        args.all_files = options.extensions.is_empty();
+        args.extensions = options.extensions;
+        args.hidden = options.hidden;
+        args.no_ignore = options.no_ignore;
+        // Walk errors are the caller's to report, not printed to stderr.
+        args.no_messages = true;
+        let keywords = collect_patterns(&args);
+        let re = try_compile_patterns(&keywords, ignores_case(&args, &keywords), &args)
+            .map_err(PatternError)?;
+        let opener = FileOpener::new(&args, &re);
+        Ok(Searcher { args, re, opener })
+    }
+
+    /// Returns the matches in the file at `path`, in order. Binary files are
+    /// searched as text.
+    pub fn search_path(&self, path: &Path) -> io::Result<Vec<Match>> {
+        let mut found = Vec::new();
+        let _ = self.search_file_with(path, &mut |m| {
+            found.push(m.clone());
+            ControlFlow::Continue(())
+        })?;
+        Ok(found)
+    }
+
+    /// Searches `root` as the command line would search it as a prefix — a
+    /// file as-is, a directory walked with the ignore, hidden, and extension
+    /// rules from the options — and hands each match to `callback` as soon as it is found,
+    /// without collecting them. Returning `ControlFlow::Break` from the
+    /// callback ends the search, so a caller that only needs to know whether
+    /// anything matches can stop at the first match. Entries the walk cannot
+    /// read are skipped; a file that cannot be read ends the search with its
+    /// error.
+    pub fn search_with<F>(&self, root: &Path, mut callback: F) -> io::Result<ControlFlow<()>>
+    where
+        F: FnMut(&Match) -> ControlFlow<()>,
+    {
+        let filter = FileFilter::new(&self.args);
+        let mut errored = false;
+        let prefixes = [root.to_string_lossy().into_owned()];
+        let mut paths = collect_paths(&prefixes, &self.args, &filter, &mut errored);
+        sort_paths(&mut paths, &self.args);
+        for path in &paths {
+            if self.search_file_with(path, &mut callback)?.is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Hands the matches in one file to `callback`.
+    fn search_file_with(
+        &self,
+        path: &Path,
+        callback: &mut dyn FnMut(&Match) -> ControlFlow<()>,
+    ) -> io::Result<ControlFlow<()>> {
+        let reader = self.opener.open(path, &self.args)?;
+        for_each_match(reader, path, &self.re, &self.args, callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(flags: &[&str]) -> Args {
+        let argv = std::iter::once("ggrep").chain(flags.iter().copied());
+        Args::try_parse_from(argv).expect("valid flags")
+    }
+
+    /// Uncolored output, with `label` in front of lines unless it is empty.
+    fn plain_style(args: &Args, label: &str) -> OutputStyle {
+        OutputStyle {
+            match_color: None,
+            line_color: None,
+            path_color: None,
+            filename: !label.is_empty(),
+            line_number: args.line_number,
+            heading: false,
+            null: false,
+            path_separator: None,
+            path_form: PathForm::AsGiven,
+            eol: '\n',
+            group_separator: args.group_separator.clone(),
+            context_separator: args.context_separator.clone(),
+            field_separator: args.field_separator.clone(),
+            seen: None,
+        }
+    }
+
+    /// Searches `input` as the command line would with `flags`, showing
+    /// `label` in front of output lines unless it is empty; returns the
+    /// output and whether the input counts as a hit for the exit status.
+    fn search(flags: &[&str], label: &str, input: &str) -> (String, bool) {
+        let args = parse(flags);
+        let re = build_matcher(&args);
+        let style = plain_style(&args, label);
+        let mut out = Vec::new();
+        let counts = search_reader(input.as_bytes(), label, &re, &args, &style, &mut out)
+            .expect("in-memory search");
+        let success = counts.success(&args);
+        (String::from_utf8(out).expect("UTF-8 output"), success)
+    }
+
+    /// A labelled in-memory input: `(label, contents)`.
+    type Input = (&'static str, &'static str);
+
+    /// Searches each input in turn; returns the combined output and the exit
+    /// status.
+    fn search_inputs(flags: &[&str], inputs: &[Input]) -> (String, ExitCode) {
+        let mut output = String::new();
+        let mut any_match = false;
+        for (label, input) in inputs {
+            let (out, matched) = search(flags, label, input);
+            output += &out;
+            any_match |= matched;
+        }
+        let args = parse(flags);
+        (output, exit_code(any_match, false, &args))
+    }
+
+    /// Inputs for the -v × -l/-L/-c matrix: mixed lines, only matching
+    /// lines, and no matching line for the pattern "foo".
+    const MATRIX: [Input; 3] = [("a", "foo\nbar\n"), ("b", "foo\nfoo\n"), ("c", "bar\n")];
+
+    #[test]
+    fn list_files_matrix() {
+        let cases: [(&[&str], &str); 4] = [
+            (&["-l"], "a\nb\n"),
+            (&["-v", "-l"], "a\nc\n"),
+            (&["-L"], "c\n"),
+            (&["-v", "-L"], "b\n"),
+        ];
+        for (flags, expected) in cases {
+            let flags = [flags, &["-e", "foo"]].concat();
+            let (out, status) = search_inputs(&flags, &MATRIX);
+            assert_eq!(out, expected, "{:?}", flags);
+            assert_eq!(status, ExitCode::SUCCESS, "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn list_files_matrix_without_hits() {
+        let cases: [(&[&str], &[Input]); 4] = [
+            (&["-l"], &MATRIX[2..]),
+            (&["-v", "-l"], &MATRIX[1..2]),
+            (&["-L"], &MATRIX[..2]),
+            (&["-v", "-L"], &[MATRIX[0], MATRIX[2]]),
+        ];
+        for (flags, inputs) in cases {
+            let flags = [flags, &["-e", "foo"]].concat();
+            let (out, status) = search_inputs(&flags, inputs);
+            assert_eq!(out, "", "{:?}", flags);
+            assert_eq!(status, ExitCode::FAILURE, "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn count_matrix() {
+        let (out, status) = search_inputs(&["-c", "-e", "foo"], &MATRIX);
+        assert_eq!(out, "a:1\nb:2\n");
+        assert_eq!(status, ExitCode::SUCCESS);
+        let (out, status) = search_inputs(&["-v", "-c", "-e", "foo"], &MATRIX);
+        assert_eq!(out, "a:1\nc:1\n");
+        assert_eq!(status, ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn count_matrix_without_hits() {
+        let (out, status) = search_inputs(&["-c", "--include-zero", "-e", "foo"], &MATRIX[2..]);
+        assert_eq!(out, "c:0\n");
+        assert_eq!(status, ExitCode::FAILURE);
+        let (out, status) = search_inputs(&["-v", "-c", "-e", "foo"], &MATRIX[1..2]);
+        assert_eq!(out, "");
+        assert_eq!(status, ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn word_regexp_bounds_the_whole_alternation() {
+        let (out, matched) = search(
+            &["-o", "-w", "-e", "foo|bar"],
+            "",
+            "foobar foo bar barfoo\n",
+        );
+        assert_eq!(out, "foo\nbar\n");
+        assert!(matched);
+    }
+
+    #[test]
+    fn ignore_case_folds_non_ascii_letters() {
+        let input = "ÄPFEL\näpfel\nApfel\n";
+        for flags in [
+            &["-i", "-e", "äpfel"][..],
+            &["-i", "-e", "ÄPFEL"],
+            &["-F", "-i", "-e", "äPfEl"],
+        ] {
+            let (out, _) = search(flags, "", input);
+            assert_eq!(out, "ÄPFEL\näpfel\n", "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn ascii_folds_only_ascii_letters() {
+        let input = "ÄPFEL\näpfel\nApfel\n";
+        let (out, _) = search(&["-i", "--ascii", "-e", "äpfel"], "", input);
+        assert_eq!(out, "äpfel\n");
+        let (out, _) = search(&["-i", "--ascii", "-e", "ÄPFEL"], "", input);
+        assert_eq!(out, "ÄPFEL\n");
+        let (out, _) = search(&["-i", "--ascii", "-e", "apfel"], "", input);
+        assert_eq!(out, "Apfel\n");
+    }
+
+    #[test]
+    fn ignore_case_fixed_matches_the_literal_in_any_case() {
+        let input = "é A.B axb\nnone\na.b\n";
+        let (out, _) = search(&["-F", "-i", "-e", "a.b"], "", input);
+        assert_eq!(out, "é A.B axb\na.b\n");
+        let (out, matched) = search(&["-F", "-i", "-e", "a.b"], "", "axb\n");
+        assert_eq!(out, "");
+        assert!(!matched);
+    }
+
+    #[test]
+    fn ignore_case_fixed_spans_cover_the_original_text() {
+        // Columns count characters and offsets count bytes, so the two-byte
+        // "é" sets them apart.
+        let input = "é A.B axb\nnone\na.b\n";
+        let (out, _) = search(&["-F", "-i", "-o", "-e", "a.b"], "", input);
+        assert_eq!(out, "A.B\na.b\n");
+        let (out, _) = search(&["-F", "-i", "-o", "--column", "-e", "a.b"], "", input);
+        assert_eq!(out, "3:A.B\n1:a.b\n");
+        let (out, _) = search(&["-F", "-i", "-o", "-b", "-e", "a.b"], "", input);
+        assert_eq!(out, "3:A.B\n16:a.b\n");
+    }
+
+    #[test]
+    fn mapped_scan_selects_the_lines_the_line_reader_does() {
+        let texts = [
+            "foo\r\nbar foo\n\nfoo\nbaz\nlast foo",
+            "foo\n\nx foo\n",
+            "x\nfoo\n",
+        ];
+        let patterns = [
+            "foo",
+            "^foo",
+            "foo$",
+            "^$",
+            "^",
+            "o\\s",
+            "\\n",
+            "\\r",
+            "z$",
+            "(?-sm)^foo",
+            "(?i-m)^foo$",
+        ];
+        let file = tempfile::NamedTempFile::new().expect("temporary file");
+        for text in texts {
+            fs::write(file.path(), text).expect("write");
+            for pattern in patterns {
+                let output = |mmap| {
+                    let args = parse(&[mmap, "-n", "-b", "-e", pattern]);
+                    let re = build_matcher(&args);
+                    let opener = FileOpener::new(&args, &re);
+                    let style = plain_style(&args, "");
+                    let result =
+                        search_file(file.path(), &re, &opener, &args, &style).expect("search");
+                    (result.output, result.counts.lines)
+                };
+                assert_eq!(
+                    output("--mmap"),
+                    output("--no-mmap"),
+                    "{:?} in {:?}",
+                    pattern,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scanner_skips_whole_input_anchors_and_context() {
+        for flags in [
+            &["-e", "\\Afoo"][..],
+            &["-e", "(?-m)^foo"],
+            &["-e", "(?-sm)^foo"],
+            &["-e", "(?i-m)^foo$"],
+            &["-C1", "-e", "foo"],
+            &["-v", "-e", "foo"],
+        ] {
+            let args = parse(flags);
+            assert!(
+                build_scanner(&build_matcher(&args), &args).is_none(),
+                "{:?}",
+                flags
+            );
+        }
+        for pattern in ["^foo$", "(?i)foo", "(?-s)^foo"] {
+            let args = parse(&["-e", pattern]);
+            assert!(
+                build_scanner(&build_matcher(&args), &args).is_some(),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn cache_key_covers_the_resolved_output_style() {
+        let args = parse(&["--cache", "-e", "foo"]);
+        let mut style = plain_style(&args, "a.txt");
+        let piped = ResultCache::key(&args, &style);
+        style.line_number = true;
+        assert_ne!(piped, ResultCache::key(&args, &style));
+    }
+}